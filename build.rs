@@ -0,0 +1,30 @@
+//! generate build/features.inc for the assembly sources, so that nasm sees the
+//! same cargo features as the rust code. The Makefile assembles with the build
+//! dir in the include path.
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// cargo features that the assembly sources need to know about, and the nasm
+/// macros they define
+const ASM_FEATURES: &[(&str, &str)] = &[("CARGO_FEATURE_KPTI", "KPTI")];
+
+fn main() {
+	let dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("build");
+	let inc = dir.join("features.inc");
+	let mut content =
+		String::from("; generated by build.rs from the cargo features\n");
+	for (var, define) in ASM_FEATURES {
+		if env::var_os(var).is_some() {
+			content += &format!("%define {}\n", define);
+		}
+	}
+	// only touch the file when it changes, the asm objects depend on it
+	if fs::read_to_string(&inc).ok().as_deref() != Some(content.as_str()) {
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(&inc, content).unwrap();
+	}
+	println!("cargo:rerun-if-changed=build.rs");
+	// switching back to a feature set that was built before
+	println!("cargo:rerun-if-changed=build/features.inc");
+}
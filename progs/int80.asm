@@ -1,16 +1,17 @@
 ; https://jameshfisher.com/2018/03/10/linux-assembly-hello-world/
+; with the syscall numbers of rustubs, see src/proc/syscall.rs
 global _start
 
 section .text
 
 _start:
-  mov rax, 1        ; write(
+  mov rax, 10       ; write(
   mov rdi, 1        ;   STDOUT_FILENO,
   mov rsi, msg      ;   "Hello, world!\n",
   mov rdx, msglen   ;   sizeof("Hello, world!\n")
   int 0x80
 
-  mov rax, 60       ; exit(
+  mov rax, 9        ; exit(
   mov rdi, 0        ;   EXIT_SUCCESS
   int 0x80
 
@@ -1,8 +1,10 @@
 pub mod arch_regs;
+pub mod cpuid;
 pub mod gdt;
 pub mod interrupt;
 pub mod io_port;
 pub mod misc;
+pub mod msr;
 pub mod paging;
 use core::arch::asm;
 
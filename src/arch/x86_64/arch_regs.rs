@@ -36,7 +36,8 @@ impl Default for Context64 {
 }
 
 /// `TrapFrame` is saved and restored by the interrupt handler assembly code
-/// upon interrupt entry and exit.
+/// upon interrupt entry and exit. The fields from `rip` on are the interrupt
+/// stack frame pushed by the CPU.
 #[repr(C)]
 #[repr(packed)]
 #[derive(Debug)]
@@ -54,6 +55,17 @@ pub struct TrapFrame {
 	/// `docs/interrupt.txt`) to the stack. For those who don't have error code,
 	/// we manually push a dummy value (0)
 	pub err_code: u64,
+	pub rip: u64,
+	pub cs: u64,
+	pub rflags: u64,
+	pub rsp: u64,
+	pub ss: u64,
+}
+
+impl TrapFrame {
+	/// whether the interrupted context was running in ring 3
+	#[inline]
+	pub fn from_user(&self) -> bool { self.cs & 0b11 == 0b11 }
 }
 
 /// get the current stack pointer
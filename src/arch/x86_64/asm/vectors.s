@@ -4,21 +4,53 @@
 [GLOBAL idt]
 [GLOBAL idt_descr]
 [GLOBAL vectors_start]
+[GLOBAL enter_user]
 [EXTERN trap_gate]
 
-[SECTION .data.idt]
+; cargo features, generated by build.rs
+%include "features.inc"
+
+; user segment selectors (gdt entries 3 and 4 with RPL 3), see
+; boot/startup-x86_64.s
+USER_CS equ 0x18 | 3
+USER_DS equ 0x20 | 3
+
+; everything in the .entry.* sections is part of the entry trampoline: with
+; KPTI, these are the only kernel pages mapped in the user page table.
+; see arch/x86_64/paging/kpti.rs
+; The page table switch is only assembled with KPTI defined (features.inc does
+; that for the kpti feature).
+
+[SECTION .entry.data]
+%ifdef KPTI
+[GLOBAL kpti_vars]
+
+; offsets into kpti_vars
+KPTI_USER_CR3   equ 0
+KPTI_KERNEL_CR3 equ 8
+KPTI_NOFLUSH    equ 16
+KPTI_FLUSH_USER equ 24
+
+; runtime variables for the page table switch on entry and exit, set by the
+; kpti code. With the user cr3 being 0 (no user twin) no switch happens.
+kpti_vars:
+	dq      0       ; cr3 of the user page table
+	dq      0       ; cr3 of the kernel page table
+	dq      0       ; no-flush bit for cr3 writes (bit 63 with PCID)
+	dq      0       ; non-zero: user tlb entries must be flushed on exit
+%endif
+
 ; Interrupt descriptor table with 256 entries
 ; TODO: use a interrupt stack instead of the current stack.
 idt:
 ; reserve space for 256x idt entries (16 bytes each)
 	resb    16 * 256
 
-[SECTION .data.idt_descr]
 idt_descr:
 	dw      256*8 - 1    ; 256 entries
 	dq      idt
 
-[SECTION .text.vectors]
+[SECTION .entry.text]
 %macro trap_without_err 1
 align 16
 vector_%1:
@@ -76,8 +108,15 @@ trap_without_err        31      ; RESERVED
 	trap_without_err i
 	%assign i i+1
 %endrep
-; irqs from 48 are not valid, we define one extra vector for all of them
-trap_without_err        48      ; INVALID
+; 16 local APIC vectors from 48 to 63 (timer, spurious)
+%rep 16
+	trap_without_err i
+	%assign i i+1
+%endrep
+; irqs from 64 are not valid, we define one extra vector for all of them
+trap_without_err        64      ; INVALID
+; the system call gate (0x80) follows the invalid one
+trap_without_err        128     ; SYSCALL
 
 ; common handler body
 vector_body:
@@ -93,17 +132,54 @@ vector_body:
 	push    r10
 	push    r11
 
+%ifdef KPTI
+	; switch to the kernel page table if we come from the user one.
+	; The cr3 at entry is saved below the trap frame and restored on exit.
+	mov     rcx, cr3
+	mov     rdx, kpti_vars
+	cmp     rcx, [rdx + KPTI_USER_CR3]
+	jne     .save_cr3
+	mov     rcx, [rdx + KPTI_KERNEL_CR3]
+	or      rcx, [rdx + KPTI_NOFLUSH]
+	mov     cr3, rcx
+	mov     rcx, [rdx + KPTI_USER_CR3]
+.save_cr3:
+	push    rcx
+%endif
+
 	; the generated wrapper only gives us 8 bits, mask the rest
 	and     rax, 0xff
 	; the first parameter is the interrupt (exception) number
 	mov     rdi, rax
 	; the second parameter is a pointer to the trap frame
+%ifdef KPTI
+	; (above the saved cr3)
+	lea     rsi, [rsp + 8]
+%else
 	mov     rsi, rsp
+%endif
 	; For a long jump, we need to put the (large) address in an register
 	; here reusing one of the caller clobbered regs (pushed above)
 	mov     r11, trap_gate
 	call    r11
 
+%ifdef KPTI
+	; switch back to the page table we came from
+	pop     rcx
+	mov     rdx, cr3
+	cmp     rcx, rdx
+	je      .restore
+	; keep the user tlb entries unless the mappings have changed
+	mov     rdx, kpti_vars
+	xor     r8, r8
+	xchg    r8, [rdx + KPTI_FLUSH_USER]
+	test    r8, r8
+	jnz     .switch_cr3
+	or      rcx, [rdx + KPTI_NOFLUSH]
+.switch_cr3:
+	mov     cr3, rcx
+.restore:
+%endif
 	; restore volatile registers
 	pop     r11
 	pop     r10
@@ -119,3 +195,49 @@ vector_body:
 	add     rsp, 8
 	; done
 	iretq
+
+; enter ring 3 at rdi with the user stack rsi. rdx is the top of the kernel
+; stack (the tss rsp0), where the cpu puts the trap frame on the next entry. We
+; build the frame for iretq there, the kernel stack below is abandoned.
+align 16
+enter_user:
+	cli
+	mov     rsp, rdx
+	push    USER_DS         ; ss
+	push    rsi             ; rsp
+	push    0x202           ; rflags: interrupts enabled
+	push    USER_CS         ; cs
+	push    rdi             ; rip
+
+%ifdef KPTI
+	; switch to the user page table, like the exit path of vector_body
+	mov     rdx, kpti_vars
+	mov     rcx, [rdx + KPTI_USER_CR3]
+	test    rcx, rcx
+	jz      .clear
+	xor     r8, r8
+	xchg    r8, [rdx + KPTI_FLUSH_USER]
+	test    r8, r8
+	jnz     .switch_cr3
+	or      rcx, [rdx + KPTI_NOFLUSH]
+.switch_cr3:
+	mov     cr3, rcx
+.clear:
+%endif
+	; don't leak kernel values to the user
+	xor     eax, eax
+	xor     ebx, ebx
+	xor     ecx, ecx
+	xor     edx, edx
+	xor     esi, esi
+	xor     edi, edi
+	xor     ebp, ebp
+	xor     r8, r8
+	xor     r9, r9
+	xor     r10, r10
+	xor     r11, r11
+	xor     r12, r12
+	xor     r13, r13
+	xor     r14, r14
+	xor     r15, r15
+	iretq
@@ -0,0 +1,52 @@
+//! cpu feature detection
+use core::arch::asm;
+
+#[derive(Copy, Clone, Debug)]
+pub struct CpuidResult {
+	pub eax: u32,
+	pub ebx: u32,
+	pub ecx: u32,
+	pub edx: u32,
+}
+
+/// execute cpuid with `leaf` in eax and `sub` in ecx
+pub fn cpuid(leaf: u32, sub: u32) -> CpuidResult {
+	let (eax, ebx, ecx, edx): (u32, u64, u32, u32);
+	// rbx is reserved by llvm, we can't use it as operand directly
+	unsafe {
+		asm!(
+			"mov {tmp}, rbx",
+			"cpuid",
+			"xchg {tmp}, rbx",
+			tmp = out(reg) ebx,
+			inout("eax") leaf => eax,
+			inout("ecx") sub => ecx,
+			out("edx") edx,
+			options(nostack, preserves_flags),
+		)
+	};
+	CpuidResult { eax, ebx: ebx as u32, ecx, edx }
+}
+
+/// MONITOR/MWAIT instructions
+pub fn has_mwait() -> bool { cpuid(1, 0).ecx & (1 << 3) != 0 }
+
+/// on-chip local APIC
+pub fn has_apic() -> bool { cpuid(1, 0).edx & (1 << 9) != 0 }
+
+/// page attribute table
+pub fn has_pat() -> bool { cpuid(1, 0).edx & (1 << 16) != 0 }
+
+/// process-context identifiers (CR4.PCIDE)
+pub fn has_pcid() -> bool { cpuid(1, 0).ecx & (1 << 17) != 0 }
+
+/// INVPCID instruction
+pub fn has_invpcid() -> bool {
+	cpuid(0, 0).eax >= 7 && cpuid(7, 0).ebx & (1 << 10) != 0
+}
+
+/// the TSC runs at a constant rate in all ACPI P-, C- and T-states
+pub fn has_invariant_tsc() -> bool {
+	cpuid(0x8000_0000, 0).eax >= 0x8000_0007
+		&& cpuid(0x8000_0007, 0).edx & (1 << 8) != 0
+}
@@ -32,11 +32,21 @@ pub unsafe fn init() {
 	let (low, high) = to_tss_desc(tss0 as u64);
 	tssd[0] = low;
 	tssd[1] = high;
+	// no io permission bitmap: ring 3 can't access any io port
+	let tss = &mut *(tss0 as *mut TaskStateSegment);
+	tss.iomap_base = size_of::<TaskStateSegment>() as u16;
 	// load tss. Fuck you x86 why this one don't need to minus one?
 	// 0x28 for the 6th entry in gdt.
 	asm!("ltr {0:x}", in(reg) 0x28, options(nostack, preserves_flags));
 }
 
+/// (high) address of the gdt
+pub fn gdt_addr() -> u64 { P2V(gdt as *const () as u64).unwrap() }
+
+pub fn tss_addr() -> u64 { tss0 as *const () as u64 }
+
+/// the stack the cpu switches to on an interrupt from ring 3: the top of the
+/// running task's kernel stack. Set on every task switch.
 pub unsafe fn set_tss_ksp(ksp: u64) {
 	let tss = tss0 as *mut TaskStateSegment;
 	(*tss).privilege_stack_table[0] = ksp;
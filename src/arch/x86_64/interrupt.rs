@@ -1,4 +1,6 @@
 mod idt;
+pub mod ioapic;
+pub mod lapic;
 pub mod pic_8259;
 pub mod pit;
 pub mod plugbox;
@@ -10,6 +12,8 @@ use crate::io::*;
 use crate::machine::interrupt::plugbox::IRQ_GATE_MAP;
 use crate::proc::sched::Scheduler;
 use crate::proc::sync::*;
+use crate::proc::syscall;
+use crate::proc::task::{Task, EXIT_KILLED};
 use core::arch::asm;
 
 #[no_mangle]
@@ -18,6 +22,10 @@ extern "C" fn trap_gate(nr: u16, fp: u64) {
 	// cpu automatically masks interrupts so we are already in L3
 	if nr < 0x20 {
 		handle_exception(nr, fp);
+	} else if nr == INT::SYSCALL {
+		syscall::dispatch(unsafe { &mut *(fp as *mut TrapFrame) });
+	} else if nr == INT::APIC_SPURIOUS {
+		// not a real interrupt, it must not be acknowledged
 	} else {
 		unsafe { handle_irq(nr) };
 	}
@@ -37,6 +45,11 @@ unsafe fn handle_irq(nr: u16) {
 	};
 	// execute the prologue
 	irq_gate.call_prologue();
+	// the PIC does auto EOI. Interrupts are off until the epilogue, so the
+	// one we acknowledge is the one in service.
+	if lapic::is_enabled() {
+		lapic::eoi();
+	}
 	let epi = irq_gate.get_epilogue();
 	if epi.is_none() {
 		// TODO? we could also take a look into the epilogue queue here when the
@@ -108,6 +121,14 @@ fn handle_exception(nr: u16, fp: u64) {
 			let fault_address = fault::get_fault_addr();
 			fault::page_fault_handler(frame, fault_address)
 		}
+		// a user program can't bring the kernel down, it only kills itself
+		_ if frame.from_user() => {
+			let t = Task::current().unwrap();
+			let rip = frame.rip;
+			println!("[PID {}] killed: trap {} rip {:#X}", t.pid, nr, rip);
+			interrupt_enable();
+			t.exit(EXIT_KILLED);
+		}
 		_ => {
 			sprint!("[trap {}] {:#X?}", nr, frame);
 			unsafe { asm!("hlt") };
@@ -147,3 +168,29 @@ pub fn init() {
 	idt::init();
 	pic_8259::init();
 }
+
+/// unmask legacy irq `irq` (see [pic_8259::PicDeviceInt]) at the IOAPIC, or
+/// at the PIC if there is none
+pub fn allow(irq: u8) {
+	if ioapic::is_enabled() {
+		ioapic::allow(irq);
+	} else {
+		pic_8259::allow(irq);
+	}
+}
+
+pub fn forbid(irq: u8) {
+	if ioapic::is_enabled() {
+		ioapic::forbid(irq);
+	} else {
+		pic_8259::forbid(irq);
+	}
+}
+
+pub fn is_masked(irq: u8) -> bool {
+	if ioapic::is_enabled() {
+		ioapic::is_masked(irq)
+	} else {
+		pic_8259::is_masked(irq)
+	}
+}
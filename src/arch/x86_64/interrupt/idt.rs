@@ -1,4 +1,5 @@
 use crate::defs::HWDefs::*;
+use crate::defs::IntNumber as INT;
 use crate::io::*;
 use crate::ExternSyms::{idt, idt_descr, vectors_start};
 use core::arch::asm;
@@ -25,6 +26,9 @@ pub fn init() {
 	for i in IDT_VALID..IDT_CAPACITY {
 		gate_descriptors[i].set_default_interrupt(offset_inv as u64);
 	}
+	// the syscall vector is right after the invalid one
+	let offset_sys = offset_inv + VECTOR_SIZE;
+	gate_descriptors[INT::SYSCALL as usize].set_syscall(offset_sys as u64);
 	// set idtr
 	unsafe { asm! ("lidt [{}]", in(reg) idt_descr) }
 }
@@ -71,4 +75,9 @@ impl GateDescriptor64 {
 		self.ist = 0;
 		self.res0 = 0;
 	}
+	/// like the default interrupt, but DPL = 3 so that ring 3 can `int` it
+	fn set_syscall(&mut self, offset: u64) {
+		self.set_default_interrupt(offset);
+		self.attrs = 0xee;
+	}
 }
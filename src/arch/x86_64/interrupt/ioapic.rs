@@ -0,0 +1,256 @@
+//! the IOAPIC: routes the device interrupts to the local APIC, replacing the
+//! legacy PIC.
+//!
+//! The IOAPICs and the interrupt source overrides come from the ACPI MADT. The
+//! legacy (ISA) irqs keep their PIC vectors (0x20 + irq), so the handlers in
+//! the plugbox don't care who delivers them. An override may move an ISA irq
+//! to another global system interrupt (GSI), e.g. the PIT is usually on GSI 2,
+//! and may change its polarity and trigger mode.
+use crate::arch::x86_64::interrupt::{lapic, pic_8259};
+use crate::io::*;
+use crate::machine::acpi;
+use crate::machine::device_io::{Mmio, MmioRegion};
+use crate::machine::interrupt::{irq_restore, irq_save};
+use crate::proc::sync::L3Sync;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// the first vector of the ISA irqs, same as with the PIC
+const ISA_VECTOR_OFFSET: u8 = 0x20;
+const ISA_IRQS: usize = 16;
+/// the irq can't be routed: its GSI was taken by an override
+const NO_GSI: u32 = u32::MAX;
+
+static IOAPICS: L3Sync<Vec<IoApic>> = L3Sync::new(Vec::new());
+/// where the ISA irqs go, after the overrides
+static ISA_ROUTES: L3Sync<[Route; ISA_IRQS]> = L3Sync::new(
+	[Route {
+		gsi: 0,
+		active_low: false,
+		level: false,
+	}; ISA_IRQS],
+);
+/// the IOAPIC is in charge, the PIC is masked
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+struct IoApic {
+	/// virtual address of the registers
+	va: u64,
+	/// the first GSI it handles
+	gsi_base: u32,
+	/// number of redirection entries
+	entries: u32,
+}
+
+#[derive(Copy, Clone)]
+struct Route {
+	gsi: u32,
+	active_low: bool,
+	level: bool,
+}
+
+/// redirection entry bits
+const REDIR_ACTIVE_LOW: u64 = 1 << 13;
+const REDIR_LEVEL: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+const REDIR_DEST_SHIFT: u64 = 56;
+
+/// MADT entry types
+const MADT_IOAPIC: u8 = 1;
+const MADT_OVERRIDE: u8 = 2;
+
+impl IoApic {
+	// indirect register access: select, then read or write the window
+	const IOREGSEL: u64 = 0x00;
+	const IOWIN: u64 = 0x10;
+	const VERSION: u32 = 0x01;
+	const REDIR_BASE: u32 = 0x10;
+
+	fn read(&self, reg: u32) -> u32 {
+		unsafe {
+			Mmio::<u32>::at(self.va + Self::IOREGSEL).write(reg);
+			Mmio::<u32>::at(self.va + Self::IOWIN).read()
+		}
+	}
+
+	fn write(&self, reg: u32, val: u32) {
+		unsafe {
+			Mmio::<u32>::at(self.va + Self::IOREGSEL).write(reg);
+			Mmio::<u32>::at(self.va + Self::IOWIN).write(val);
+		}
+	}
+
+	fn read_redir(&self, n: u32) -> u64 {
+		let lo = self.read(Self::REDIR_BASE + 2 * n) as u64;
+		let hi = self.read(Self::REDIR_BASE + 2 * n + 1) as u64;
+		hi << 32 | lo
+	}
+
+	fn write_redir(&self, n: u32, val: u64) {
+		// mask first, the entry must not fire half written
+		self.write(Self::REDIR_BASE + 2 * n, REDIR_MASKED as u32);
+		self.write(Self::REDIR_BASE + 2 * n + 1, (val >> 32) as u32);
+		self.write(Self::REDIR_BASE + 2 * n, val as u32);
+	}
+
+	fn handles(&self, gsi: u32) -> bool {
+		gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+	}
+}
+
+/// find the IOAPICs in the MADT, route the ISA irqs (masked) and take over
+/// from the PIC. Returns false if there is no IOAPIC, call after
+/// [lapic::init] with interrupts disabled.
+pub fn init() -> bool {
+	if !lapic::is_enabled() {
+		return false;
+	}
+	let Some(madt) = acpi::find_table(b"APIC") else {
+		println!("[init] ioapic: no madt");
+		return false;
+	};
+	let ioapics = IOAPICS.l3_get_ref_mut();
+	let routes = ISA_ROUTES.l3_get_ref_mut();
+	let mut overridden = [false; ISA_IRQS];
+	for (irq, r) in routes.iter_mut().enumerate() {
+		// ISA default: edge triggered, active high, identity mapped
+		*r = Route {
+			gsi: irq as u32,
+			active_low: false,
+			level: false,
+		};
+	}
+	// the entries follow the local APIC address and the flags
+	let mut entries = madt.body().get(8..).unwrap_or(&[]);
+	while let [ty, len, ..] = *entries {
+		let len = len as usize;
+		if len < 2 || len > entries.len() {
+			break;
+		}
+		let e = &entries[..len];
+		match ty {
+			MADT_IOAPIC if len >= 12 => {
+				let pa = u32::from_le_bytes(e[4..8].try_into().unwrap());
+				let gsi_base = u32::from_le_bytes(e[8..12].try_into().unwrap());
+				match map_ioapic(pa as u64, gsi_base) {
+					Some(a) => ioapics.push(a),
+					None => println!("[init] ioapic: can't map {:#X}", pa),
+				}
+			}
+			MADT_OVERRIDE if len >= 10 => {
+				// bus (0 is ISA), source irq, gsi, flags
+				let src = e[3] as usize;
+				let gsi = u32::from_le_bytes(e[4..8].try_into().unwrap());
+				let flags = u16::from_le_bytes(e[8..10].try_into().unwrap());
+				if e[2] == 0 && src < ISA_IRQS {
+					// 0b11 is active low / level, anything else keeps the
+					// ISA default
+					routes[src] = Route {
+						gsi,
+						active_low: flags & 0b11 == 0b11,
+						level: (flags >> 2) & 0b11 == 0b11,
+					};
+					overridden[src] = true;
+				}
+			}
+			_ => {}
+		}
+		entries = &entries[len..];
+	}
+	if ioapics.is_empty() {
+		println!("[init] ioapic: none found");
+		return false;
+	}
+	// an identity mapped irq loses its GSI to an override, e.g. irq 2 (the
+	// PIC cascade) to the PIT
+	for irq in 0..ISA_IRQS {
+		let gsi = routes[irq].gsi;
+		let taken = (0..ISA_IRQS)
+			.any(|o| o != irq && overridden[o] && routes[o].gsi == gsi);
+		if !overridden[irq] && taken {
+			routes[irq].gsi = NO_GSI;
+		}
+	}
+	// everything masked until allowed
+	for a in ioapics.iter() {
+		for n in 0..a.entries {
+			a.write_redir(n, REDIR_MASKED);
+		}
+	}
+	for irq in 0..ISA_IRQS as u8 {
+		route(irq, true);
+	}
+	// the PIC is out of the game
+	pic_8259::disable();
+	lapic::mask_extint();
+	ENABLED.store(true, Ordering::Relaxed);
+	for a in ioapics.iter() {
+		println!(
+			"[init] ioapic: gsi {}-{}",
+			a.gsi_base,
+			a.gsi_base + a.entries - 1
+		);
+	}
+	return true;
+}
+
+fn map_ioapic(pa: u64, gsi_base: u32) -> Option<IoApic> {
+	let regs = MmioRegion::map(pa, 0x20).ok()?;
+	let mut a = IoApic { va: regs.va(), gsi_base, entries: 0 };
+	// the registers stay mapped for good
+	mem::forget(regs);
+	a.entries = ((a.read(IoApic::VERSION) >> 16) & 0xff) + 1;
+	Some(a)
+}
+
+/// program the redirection entry of ISA irq `irq`, call with interrupts
+/// disabled
+fn route(irq: u8, masked: bool) -> Option<()> {
+	let r = *ISA_ROUTES.l3_get_ref().get(irq as usize)?;
+	let a = IOAPICS.l3_get_ref().iter().find(|a| a.handles(r.gsi))?;
+	let mut e = (ISA_VECTOR_OFFSET + irq) as u64
+		| (lapic::id() as u64) << REDIR_DEST_SHIFT;
+	if r.active_low {
+		e |= REDIR_ACTIVE_LOW;
+	}
+	if r.level {
+		e |= REDIR_LEVEL;
+	}
+	if masked {
+		e |= REDIR_MASKED;
+	}
+	a.write_redir(r.gsi - a.gsi_base, e);
+	Some(())
+}
+
+/// the IOAPIC delivers the device interrupts
+pub fn is_enabled() -> bool { ENABLED.load(Ordering::Relaxed) }
+
+/// unmask ISA irq `irq` (see [pic_8259::PicDeviceInt])
+pub fn allow(irq: u8) {
+	let r = irq_save();
+	route(irq, false);
+	irq_restore(r);
+}
+
+pub fn forbid(irq: u8) {
+	let r = irq_save();
+	route(irq, true);
+	irq_restore(r);
+}
+
+/// true if the irq is masked or not routed at all
+pub fn is_masked(irq: u8) -> bool {
+	let r = irq_save();
+	let gsi = ISA_ROUTES
+		.l3_get_ref()
+		.get(irq as usize)
+		.map_or(NO_GSI, |r| r.gsi);
+	let masked = match IOAPICS.l3_get_ref().iter().find(|a| a.handles(gsi)) {
+		Some(a) => a.read_redir(gsi - a.gsi_base) & REDIR_MASKED != 0,
+		None => true,
+	};
+	irq_restore(r);
+	masked
+}
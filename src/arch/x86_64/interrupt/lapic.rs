@@ -0,0 +1,161 @@
+//! the local APIC: interrupt delivery to this cpu and its timer.
+//!
+//! The legacy PIC stays in place behind it until the IOAPIC takes over (see
+//! [super::ioapic]): LINT0 is set up as ExtINT (virtual wire mode), so the PIC
+//! interrupts that are not masked (the keyboard) still come in. The PIT line
+//! is left masked, the APIC timer replaces it.
+//!
+//! Every interrupt delivered by the APIC must be acknowledged with [eoi] (the
+//! irq handler does that after the prologue), except for the spurious one.
+use crate::arch::x86_64::cpuid;
+use crate::arch::x86_64::interrupt::pit::PIT;
+use crate::arch::x86_64::msr::{rdmsr, wrmsr, IA32_APIC_BASE};
+use crate::defs::IntNumber as INT;
+use crate::io::*;
+use crate::machine::clockevent::{self, ClockEventDevice};
+use crate::machine::device_io::{Mmio, MmioRegion};
+use crate::proc::sched::Scheduler;
+use crate::proc::sync::IRQHandlerEpilogue;
+use crate::proc::timer;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub struct LAPIC {}
+
+/// virtual address of the registers, 0 if the APIC is not in use
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// timer ticks per ms (after the divider)
+static TIMER_KHZ: AtomicU64 = AtomicU64::new(0);
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+impl LAPIC {
+	// registers
+	const ID: u64 = 0x20;
+	const TPR: u64 = 0x80;
+	const EOI: u64 = 0xb0;
+	const SVR: u64 = 0xf0;
+	const ESR: u64 = 0x280;
+	const LVT_TIMER: u64 = 0x320;
+	const LVT_LINT0: u64 = 0x350;
+	const LVT_LINT1: u64 = 0x360;
+	const LVT_ERROR: u64 = 0x370;
+	const TIMER_INIT: u64 = 0x380;
+	const TIMER_CURRENT: u64 = 0x390;
+	const TIMER_DIV: u64 = 0x3e0;
+	/// SVR: software enable
+	const SVR_ENABLE: u32 = 1 << 8;
+	/// LVT: masked
+	const LVT_MASKED: u32 = 1 << 16;
+	/// LVT delivery modes
+	const LVT_NMI: u32 = 0b100 << 8;
+	const LVT_EXTINT: u32 = 0b111 << 8;
+	/// timer divider 16
+	const DIV_16: u32 = 0b0011;
+	const CALIBRATE_MS: u64 = 10;
+
+	fn reg(off: u64) -> &'static Mmio<u32> {
+		unsafe { Mmio::at(BASE.load(Ordering::Relaxed) + off) }
+	}
+
+	/// timer ticks in 10 ms, the best of a few runs
+	fn calibrate_timer() -> u64 {
+		Self::reg(Self::TIMER_DIV).write(Self::DIV_16);
+		Self::reg(Self::LVT_TIMER).write(Self::LVT_MASKED);
+		let mut best = u64::MAX;
+		for _ in 0..3 {
+			Self::reg(Self::TIMER_INIT).write(u32::MAX);
+			PIT::busy_wait_ms(Self::CALIBRATE_MS);
+			let elapsed = u32::MAX - Self::reg(Self::TIMER_CURRENT).read();
+			best = u64::min(best, elapsed as u64);
+		}
+		Self::reg(Self::TIMER_INIT).write(0);
+		best
+	}
+}
+
+/// enable the APIC of this cpu and calibrate its timer. Returns false if
+/// there is no APIC, interrupts stay with the PIC then. Call with interrupts
+/// disabled, after mm init.
+pub fn init() -> bool {
+	if !cpuid::has_apic() {
+		println!("[init] lapic: not present");
+		return false;
+	}
+	let msr = unsafe { rdmsr(IA32_APIC_BASE) };
+	let pa = msr & APIC_BASE_ADDR_MASK;
+	let Ok(regs) = MmioRegion::map(pa, 0x1000) else {
+		println!("[init] lapic: can't map {:#X}", pa);
+		return false;
+	};
+	unsafe { wrmsr(IA32_APIC_BASE, msr | APIC_BASE_ENABLE) };
+	BASE.store(regs.va(), Ordering::Relaxed);
+	// the registers stay mapped for good
+	mem::forget(regs);
+
+	// accept all priorities
+	LAPIC::reg(LAPIC::TPR).write(0);
+	LAPIC::reg(LAPIC::LVT_LINT0).write(LAPIC::LVT_EXTINT);
+	LAPIC::reg(LAPIC::LVT_LINT1).write(LAPIC::LVT_NMI);
+	LAPIC::reg(LAPIC::LVT_ERROR).write(LAPIC::LVT_MASKED);
+	// the error status must be written before it is read
+	LAPIC::reg(LAPIC::ESR).write(0);
+	LAPIC::reg(LAPIC::ESR).read();
+	LAPIC::reg(LAPIC::SVR).write(LAPIC::SVR_ENABLE | INT::APIC_SPURIOUS as u32);
+	eoi();
+
+	let khz = LAPIC::calibrate_timer() / LAPIC::CALIBRATE_MS;
+	TIMER_KHZ.store(khz, Ordering::Relaxed);
+	println!("[init] lapic: id {} @ {:#X}, timer {} kHz", id(), pa, khz);
+	return true;
+}
+
+pub fn is_enabled() -> bool { BASE.load(Ordering::Relaxed) != 0 }
+
+/// the APIC id of this cpu
+pub fn id() -> u8 { (LAPIC::reg(LAPIC::ID).read() >> 24) as u8 }
+
+/// stop taking interrupts from the legacy PIC
+pub fn mask_extint() {
+	LAPIC::reg(LAPIC::LVT_LINT0).write(LAPIC::LVT_EXTINT | LAPIC::LVT_MASKED);
+}
+
+/// acknowledge the interrupt in service
+#[inline]
+pub fn eoi() { LAPIC::reg(LAPIC::EOI).write(0); }
+
+/// the APIC timer in one-shot mode
+pub static LAPIC_CLOCKEVENT: LAPIC = LAPIC {};
+
+impl ClockEventDevice for LAPIC {
+	fn name(&self) -> &'static str { "lapic" }
+
+	fn program(&self, ns: u64) -> u64 {
+		let khz = TIMER_KHZ.load(Ordering::Relaxed);
+		let ns = u64::min(ns, self.max_delta());
+		let count = u64::clamp(ns * khz / 1_000_000, 1, u32::MAX as u64);
+		// one-shot is mode 0
+		Self::reg(Self::LVT_TIMER).write(INT::APIC_TIMER as u32);
+		Self::reg(Self::TIMER_INIT).write(count as u32);
+		count * 1_000_000 / khz
+	}
+
+	fn stop(&self) { Self::reg(Self::TIMER_INIT).write(0); }
+
+	fn max_delta(&self) -> u64 {
+		u32::MAX as u64 * 1_000_000 / TIMER_KHZ.load(Ordering::Relaxed)
+	}
+}
+
+impl IRQHandlerEpilogue for LAPIC {
+	unsafe fn do_prologue() {
+		if clockevent::tick_due() {
+			Scheduler::tick();
+		}
+	}
+	unsafe fn do_epilogue() {
+		timer::run_expired();
+		clockevent::reprogram();
+	}
+}
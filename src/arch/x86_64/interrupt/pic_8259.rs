@@ -11,6 +11,7 @@ pub struct PicDeviceInt;
 impl PicDeviceInt {
 	pub const TIMER: u8 = 0;
 	pub const KEYBOARD: u8 = 1;
+	pub const RTC: u8 = 8;
 }
 
 pub fn init() {
@@ -34,6 +35,12 @@ pub fn init() {
 	outb(IMR2, 0xff);
 }
 
+/// mask all lines, when the IOAPIC takes over
+pub fn disable() {
+	outb(IMR1, 0xff);
+	outb(IMR2, 0xff);
+}
+
 // 8-bit registers IMR1 and IMR2 registers hold interrupt masking bit 0~7 and
 // 8~15; if an interrupt is masked(set 1) on the respective bit, it's disabled
 pub fn allow(interrupt: u8) {
@@ -59,9 +66,9 @@ pub fn forbid(interrupt: u8) {
 pub fn is_masked(interrupt: u8) -> bool {
 	if interrupt < 8 {
 		let val = inb(IMR1);
-		val & (interrupt) != 0
+		val & (1 << interrupt) != 0
 	} else {
 		let val = inb(IMR2);
-		val & (interrupt - 8) != 0
+		val & (1 << (interrupt - 8)) != 0
 	}
 }
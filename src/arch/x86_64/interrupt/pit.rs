@@ -1,20 +1,24 @@
 // x86 programmable interrupt timer
 // TODO this is a device, should not live under interrupt module
-// TODO there should be an machine level timer abstraction
+use crate::arch::x86_64::misc::rdtsc;
+use crate::machine::clockevent::{self, ClockEventDevice};
 use crate::machine::device_io::IOPort;
-use crate::machine::time;
-use crate::proc::sched::SET_NEED_RESCHEDULE;
-use crate::proc::sync::bellringer::BellRinger;
+use crate::proc::sched::Scheduler;
 use crate::proc::sync::IRQHandlerEpilogue;
-use crate::proc::task::Task;
+use crate::proc::timer;
 // use crate::proc::sched::
 pub struct PIT {}
 
 impl PIT {
 	const CTRL_PORT: IOPort = IOPort::new(0x43);
 	const DATA_PORT: IOPort = IOPort::new(0x40);
+	const CH2_PORT: IOPort = IOPort::new(0x42);
+	/// gate of channel 2 (bit 0) and its output (bit 5)
+	const CH2_GATE_PORT: IOPort = IOPort::new(0x61);
 	// 1193182 Hz is roughly 838 ns
 	const PIT_BASE_NS: u64 = 838;
+	const PIT_HZ: u64 = 1193182;
+	const MAX_COUNT: u64 = 0xffff;
 	// max is around 54918 us (54 ms)
 	pub fn set_interval(us: u64) -> u64 {
 		let mut divider =
@@ -31,19 +35,65 @@ impl PIT {
 		Self::DATA_PORT.outb(((divider & 0xff00) >> 8) as u8);
 		divider * Self::PIT_BASE_NS
 	}
+
+	/// busy wait `ms` (at most 54) milliseconds with channel 2, which doesn't
+	/// raise interrupts. For calibrating other timers.
+	pub fn busy_wait_ms(ms: u64) {
+		let count = u64::min(Self::PIT_HZ * ms / 1000, Self::MAX_COUNT);
+		// gate on, speaker off
+		let gate = Self::CH2_GATE_PORT.inb();
+		Self::CH2_GATE_PORT.outb((gate & !0x2) | 0x1);
+		// channel 2, lobyte/hibyte, mode 0: out goes high at terminal count
+		Self::CTRL_PORT.outb(0xb0);
+		Self::CH2_PORT.outb((count & 0xff) as u8);
+		Self::CH2_PORT.outb((count >> 8) as u8);
+		while Self::CH2_GATE_PORT.inb() & 0x20 == 0 {}
+		Self::CH2_GATE_PORT.outb(gate);
+	}
+
+	/// measure the TSC frequency (kHz). Takes 10 ms.
+	pub fn calibrate_tsc() -> u64 {
+		const MS: u64 = 10;
+		let start = rdtsc();
+		Self::busy_wait_ms(MS);
+		(rdtsc() - start) / MS
+	}
+}
+
+/// the PIT in one-shot mode (mode 0)
+pub static PIT_CLOCKEVENT: PIT = PIT {};
+
+impl ClockEventDevice for PIT {
+	fn name(&self) -> &'static str { "pit" }
+
+	fn program(&self, ns: u64) -> u64 {
+		let count = u64::clamp(ns / Self::PIT_BASE_NS, 1, Self::MAX_COUNT);
+		// channel 0, lobyte/hibyte, mode 0, counting starts with the count
+		Self::CTRL_PORT.outb(0x30);
+		Self::DATA_PORT.outb((count & 0xff) as u8);
+		Self::DATA_PORT.outb((count >> 8) as u8);
+		count * Self::PIT_BASE_NS
+	}
+
+	fn stop(&self) {
+		// mode 0 waits for a count after the control word
+		Self::CTRL_PORT.outb(0x30);
+	}
+
+	fn max_delta(&self) -> u64 { Self::MAX_COUNT * Self::PIT_BASE_NS }
 }
 
 impl IRQHandlerEpilogue for PIT {
 	unsafe fn do_prologue() {
 		// half measure: we can't set the resschedule flag when the first
-		// task is not yet running i.e. before kickoff(). We need an aditional
-		// check here to see if there is a valid task struct on the kernel stack;
-		time::tick();
-		let _task = Task::current();
-		if _task.is_none() {
-			return;
+		// task is not yet running i.e. before kickoff(). Scheduler::tick
+		// checks if there is a valid task struct on the kernel stack;
+		if clockevent::tick_due() {
+			Scheduler::tick();
 		}
-		let _ = SET_NEED_RESCHEDULE();
 	}
-	unsafe fn do_epilogue() { BellRinger::check_all(); }
+	unsafe fn do_epilogue() {
+		timer::run_expired();
+		clockevent::reprogram();
+	}
 }
@@ -1,8 +1,10 @@
 //! Registrar of IRQ handling routines
 
+use crate::arch::x86_64::interrupt::lapic::LAPIC;
 use crate::arch::x86_64::interrupt::pit::PIT;
 use crate::defs::IntNumber as INT;
 use crate::machine::keyctrl::KeyboardDriver;
+use crate::machine::rtc::RTC;
 use crate::proc::sync::IRQGate;
 use crate::proc::sync::IRQHandlerEpilogue;
 use alloc::collections::BTreeMap;
@@ -13,5 +15,7 @@ lazy_static! {
 	pub static ref IRQ_GATE_MAP: BTreeMap<u16, IRQGate> = [
 		(INT::TIMER,    PIT::get_gate()),
 		(INT::KEYBOARD, KeyboardDriver::get_gate()),
+		(INT::RTC,      RTC::get_gate()),
+		(INT::APIC_TIMER, LAPIC::get_gate()),
 	].iter().copied().collect();
 }
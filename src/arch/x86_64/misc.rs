@@ -2,11 +2,44 @@
 //! before they find better place to go.
 //! asm code goes to asm/misc.s
 
+use core::arch::asm;
+
 extern "C" {
 	fn _delay();
 }
 
+/// the address mwait monitors, nobody writes it: we only wait for interrupts
+static MWAIT_MONITOR: u64 = 0;
+
 /// delays for several cycles. Used to fill sequantial IO commands (for devices
 /// to react). This does literally nothing: call an empty function and return
 #[inline(always)]
 pub fn delay() { unsafe { _delay() }; }
+
+/// enable interrupts and sleep until the next one. With `mwait` (if the cpu has
+/// it) the cpu may enter a deeper sleep state than with hlt. In both cases the
+/// interrupt shadow of sti makes sure no interrupt slips in before the sleep.
+#[inline]
+pub fn wait_for_interrupt(mwait: bool) {
+	if !mwait {
+		unsafe { asm!("sti; hlt") };
+		return;
+	}
+	unsafe {
+		asm!(
+			"monitor",
+			in("rax") &MWAIT_MONITOR as *const u64,
+			in("ecx") 0,
+			in("edx") 0,
+		);
+		asm!("sti; mwait", in("eax") 0, in("ecx") 0);
+	}
+}
+
+/// read the time stamp counter
+#[inline]
+pub fn rdtsc() -> u64 {
+	let (lo, hi): (u32, u32);
+	unsafe { asm!("rdtsc", out("eax") lo, out("edx") hi) };
+	((hi as u64) << 32) | lo as u64
+}
@@ -0,0 +1,33 @@
+//! model specific registers
+use core::arch::asm;
+
+pub const IA32_APIC_BASE: u32 = 0x1b;
+pub const IA32_PAT: u32 = 0x277;
+pub const IA32_EFER: u32 = 0xc000_0080;
+
+/// unsafe: reading a msr the cpu doesn't have raises #GP
+#[inline]
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+	let (lo, hi): (u32, u32);
+	asm!(
+		"rdmsr",
+		in("ecx") msr,
+		out("eax") lo,
+		out("edx") hi,
+		options(nomem, nostack, preserves_flags),
+	);
+	(hi as u64) << 32 | lo as u64
+}
+
+/// unsafe: writing a msr the cpu doesn't have raises #GP, and a msr can
+/// change pretty much anything.
+#[inline]
+pub unsafe fn wrmsr(msr: u32, val: u64) {
+	asm!(
+		"wrmsr",
+		in("ecx") msr,
+		in("eax") val as u32,
+		in("edx") (val >> 32) as u32,
+		options(nostack, preserves_flags),
+	);
+}
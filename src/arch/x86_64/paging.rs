@@ -1,24 +1,66 @@
 pub mod fault;
+#[cfg(feature = "kpti")]
+pub mod kpti;
 pub mod pagetable;
+pub mod pat;
+pub mod pcid;
+pub mod walk;
 use crate::defs;
 use crate::defs::rounddown_4k;
+use crate::defs::Mem;
 use crate::defs::P2V;
+use crate::fs;
 use crate::io::*;
 use crate::mm::allocate_4k_zeroed;
+use crate::mm::frame::{FrameOwner, FRAME_TABLE};
+use crate::mm::free_4k;
+use crate::mm::invlpg_root;
+use crate::mm::reclaim::lru_add;
 use crate::mm::vmm::VMArea;
+use crate::mm::vmm::VMPerms;
 use crate::mm::vmm::VMType;
+use crate::mm::zram::ZRAM;
 use core::arch::asm;
 use core::ops::Range;
-use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use fault::fill_from_file;
 pub use pagetable::*;
 /// for x86_64, return the CR3 register. this is the **physical** address of the
-/// page table root.
+/// page table root (the PCID bits are masked).
 /// TODO: use page root in task struct instead of raw cr3
 #[inline]
 pub fn get_cr3() -> u64 {
 	let cr3: u64;
 	unsafe { asm!("mov {}, cr3", out(reg) cr3) };
-	cr3
+	cr3 & !Mem::PAGE_MASK
+}
+
+/// set CR0.WP so that read-only pages are also write protected in ring 0. This
+/// is required for copy-on-write.
+pub fn enable_wp() {
+	unsafe {
+		asm!(
+			"mov {0}, cr0",
+			"or {0}, {1}",
+			"mov cr0, {0}",
+			out(reg) _,
+			const 1u64 << 16,
+		)
+	}
+}
+
+/// set CR4.PCIDE, the low 12 bits of CR3 are the PCID from now on. The current
+/// PCID must be 0.
+pub fn enable_pcid() {
+	unsafe {
+		asm!(
+			"mov {0}, cr4",
+			"or {0}, {1}",
+			"mov cr4, {0}",
+			out(reg) _,
+			const 1u64 << 17,
+		)
+	}
 }
 
 /// returns the identically mapped (+ kernel offset) virtual address of the page
@@ -26,37 +68,121 @@ pub fn get_cr3() -> u64 {
 #[inline]
 pub fn get_root() -> u64 { P2V(get_cr3()).unwrap() }
 
+/// the boot page table, its kernel half is shared by all address spaces
+static KERNEL_ROOT: AtomicU64 = AtomicU64::new(0);
+
+pub fn kernel_root() -> u64 { KERNEL_ROOT.load(Ordering::Relaxed) }
+
+/// paging setup on top of the boot page table, call after the frame table is
+/// initialized
+pub fn init() {
+	enable_wp();
+	KERNEL_ROOT.store(get_root(), Ordering::Relaxed);
+	pcid::init();
+	pat::init();
+	println!(
+		"[init] paging: pcid {}, invpcid {}, pat {}",
+		if pcid::enabled() { "on" } else { "off" },
+		if pcid::has_invpcid() { "on" } else { "off" },
+		if pat::enabled() { "on" } else { "off" },
+	);
+}
+
+/// allocate a new page table root with the kernel half (pml4 entries 256~511)
+/// of the boot page table and an empty user half. Returns the virtual address.
+pub fn new_root() -> Option<u64> {
+	let root = allocate_4k_zeroed(FrameOwner::PAGETABLE);
+	if root == 0 {
+		return None;
+	}
+	let (ktbl, tbl) = unsafe {
+		(
+			&*(kernel_root() as *const Pagetable),
+			&mut *(root as *mut Pagetable),
+		)
+	};
+	for i in 256..512 {
+		tbl.entries[i] = ktbl.entries[i].clone();
+	}
+	Some(root)
+}
+
+/// free a page table root from [new_root] with its user half: the page tables,
+/// the user frames (shared ones only lose a reference) and the swapped out
+/// pages. The kernel half is shared with all other roots and stays.
+/// unsafe: the root must not be in use anywhere.
+pub unsafe fn free_root(pt_root: u64) {
+	debug_assert_ne!(pt_root, get_root());
+	let tbl = &mut *(pt_root as *mut Pagetable);
+	for ent in tbl.entries[..256].iter_mut() {
+		free_subtree(pt_root, ent, 3, true);
+	}
+	free_4k(pt_root);
+}
+
+/// free the table `ent` points to and the tables below. `level` is the level
+/// of that table, 3 for a pdp and 1 for a pt. The leaf frames are dropped if
+/// `leaves`, otherwise they are not ours.
+pub unsafe fn free_subtree(
+	pt_root: u64,
+	ent: &mut PTE,
+	level: u8,
+	leaves: bool,
+) {
+	let f = ent.flags();
+	if !f.contains(PTEFlags::PRESENT) || f.contains(PTEFlags::HUGE_PAGE) {
+		return;
+	}
+	let tbl = P2V(ent.addr()).unwrap();
+	for e in (*(tbl as *mut Pagetable)).entries.iter_mut() {
+		if level > 1 {
+			free_subtree(pt_root, e, level - 1, leaves);
+		} else if leaves {
+			drop_leaf(pt_root, e);
+		}
+	}
+	free_4k(tbl);
+	ent.set_unused();
+}
+
+/// drop the frame or the swap slot of a user pte. Frames we don't manage
+/// (e.g. ramfs pages mapped into the user space) are left alone.
+unsafe fn drop_leaf(pt_root: u64, pte: &mut PTE) {
+	if let Some(slot) = pte.swap_slot() {
+		ZRAM.lock().free(slot);
+	} else if pte.flags().contains(PTEFlags::PRESENT) {
+		let pa = pte.addr();
+		let mut ft = FRAME_TABLE.lock();
+		let owned = ft.get_mut(pa).filter(|d| {
+			d.refcount != 0
+				&& matches!(
+					d.owner,
+					FrameOwner::ANON | FrameOwner::FILE | FrameOwner::SHM
+				)
+		});
+		if let Some(d) = owned {
+			// the reclaimer must not walk the freed tables
+			if d.rmap.is_some_and(|(r, _)| r == pt_root) {
+				d.rmap = None;
+			}
+			drop(ft);
+			free_4k(P2V(pa).unwrap());
+		}
+	}
+	pte.set_unused();
+}
+
 /// unsafe as it dereferences raw pointer pt_root. Must make sure it's a valid,
 /// 4k aligned _virtual_ address.
 // TODO use Result type instead of bool so that we can do early return with ?..
 pub unsafe fn map_vma(pt_root: u64, vma: &VMArea, do_copy: bool) -> bool {
-	// create mappings in pagetable
-	let flags = PTEFlags::PRESENT | PTEFlags::WRITABLE | PTEFlags::USER;
-	if !map_range(pt_root, &vma.vm_range, flags) {
-		println!("failed to map range");
-		return false;
-	}
-	if !do_copy {
-		return true;
-	}
 	match vma.backing {
+		// anonymous memory is populated on demand by the page fault handler
 		VMType::ANOM => {
 			return true;
 		}
 		VMType::FILE(f) => {
-			if !do_copy {
-				return true;
-			}
-			let sz = (vma.vm_range.end - vma.vm_range.start) as usize;
-			sprintln!("copy from {:p} to {:#X}", &f[0], vma.vm_range.start);
-			unsafe {
-				ptr::copy_nonoverlapping(
-					&f[0] as *const u8,
-					vma.vm_range.start as *mut u8,
-					sz,
-				)
-			}
-			return true;
+			return map_file(pt_root, vma, f, do_copy);
 		}
 		_ => {
 			println!("unknown backing");
@@ -65,10 +191,82 @@ pub unsafe fn map_vma(pt_root: u64, vma: &VMArea, do_copy: bool) -> bool {
 	}
 }
 
-pub fn map_range(pt_root: u64, r: &Range<u64>, flags: PTEFlags) -> bool {
+/// PTE flags for user pages in the vma
+pub fn vma_pte_flags(vma: &VMArea) -> PTEFlags {
+	let mut flags = PTEFlags::PRESENT | PTEFlags::USER;
+	if vma.user_perms.contains(VMPerms::W) {
+		flags |= PTEFlags::WRITABLE;
+	}
+	flags
+}
+
+/// map a file backed vma page by page. The ramfs is linked into the kernel
+/// image, so pages that are fully covered by a page aligned part of the file
+/// are mapped directly to the ramfs frames: read-only, and copy-on-write in a
+/// writable vma. The other pages get a private copy (or stay zeroed if
+/// `!do_copy`).
+unsafe fn map_file(
+	pt_root: u64,
+	vma: &VMArea,
+	f: &'static [u8],
+	do_copy: bool,
+) -> bool {
+	let flags = vma_pte_flags(vma);
+	let mut shared = flags;
+	if flags.contains(PTEFlags::WRITABLE) {
+		shared = (flags - PTEFlags::WRITABLE) | PTEFlags::COW;
+	}
+	let file_va = vma.vm_range.start;
+	let mut page = rounddown_4k(vma.vm_range.start);
+	while page < vma.vm_range.end {
+		if let Some(pa) = ramfs_frame(vma, f, page).filter(|_| do_copy) {
+			if !map_page_to(pt_root, page, pa, shared) {
+				return false;
+			}
+		} else {
+			if !map_page(pt_root, page, flags, FrameOwner::FILE) {
+				println!("failed to map page @ {:#X}", page);
+				return false;
+			}
+			let pa = get_pte(pt_root, page).unwrap().addr();
+			if do_copy {
+				fill_from_file(P2V(pa).unwrap(), page, file_va, f);
+			}
+			lru_add(pa, pt_root, page);
+		}
+		page += defs::Mem::PAGE_SIZE;
+	}
+	return true;
+}
+
+/// returns the physical ramfs frame holding the file content for `page`, if
+/// the page can be shared with the ramfs.
+fn ramfs_frame(vma: &VMArea, f: &[u8], page: u64) -> Option<u64> {
+	let file_va = vma.vm_range.start;
+	if page < file_va || page + defs::Mem::PAGE_SIZE > file_va + f.len() as u64
+	{
+		return None;
+	}
+	let src = &f[(page - file_va) as usize] as *const u8 as u64;
+	if !fs::in_ramfs(src) {
+		return None;
+	}
+	let pa = defs::K2P(src)?;
+	if !defs::is_aligned_4k(pa) {
+		return None;
+	}
+	Some(pa)
+}
+
+pub fn map_range(
+	pt_root: u64,
+	r: &Range<u64>,
+	flags: PTEFlags,
+	owner: FrameOwner,
+) -> bool {
 	let mut va_aligned = rounddown_4k(r.start);
 	while va_aligned < r.end {
-		if !map_page(pt_root, va_aligned, flags) {
+		if !map_page(pt_root, va_aligned, flags, owner) {
 			println!("failed to map page @ {:#X}", va_aligned);
 			return false;
 		}
@@ -77,57 +275,167 @@ pub fn map_range(pt_root: u64, r: &Range<u64>, flags: PTEFlags) -> bool {
 	return true;
 }
 
-/// walk the page table, create missing tables, return mapped physical frame
-pub fn map_page(pt_root: u64, va: u64, _flags: PTEFlags) -> bool {
-	let pt = pt_root as *mut Pagetable;
+/// map a fresh zeroed frame at `va`. The new frame is accounted for `owner` in
+/// the frame table.
+pub fn map_page(
+	pt_root: u64,
+	va: u64,
+	flags: PTEFlags,
+	owner: FrameOwner,
+) -> bool {
 	if !defs::is_aligned_4k(va) {
 		println!("not aligned");
 		return false;
 	}
-	let flags: u64 = _flags.bits();
-	let l4idx = pagetable::p4idx(va) as usize;
-	let l3idx = pagetable::p3idx(va) as usize;
-	let l2idx = pagetable::p2idx(va) as usize;
-	let l1idx = pagetable::p1idx(va) as usize;
-	let mut require_new = false;
-	unsafe {
-		let l4_ent = &mut (*pt).entries[l4idx];
-		let l3_tbl: *mut Pagetable;
-		if l4_ent.is_unused() || require_new {
-			l3_tbl = allocate_4k_zeroed() as *mut Pagetable;
-			l4_ent.entry = defs::V2P(l3_tbl as u64).unwrap() | flags;
-			require_new = true
+	let Some(pte) = walk_create(pt_root, va, flags) else {
+		return false;
+	};
+	if !pte.is_unused() {
+		// TODO we need to free this frame
+		panic!("PTE already taken: {:#X}", pte.entry);
+	}
+	let page = allocate_4k_zeroed(owner);
+	if page == 0 {
+		return false;
+	}
+	pte.set(defs::V2P(page).unwrap(), flags);
+	invlpg_root(pt_root, va);
+	return true;
+}
+
+/// map the existing physical frame `pa` at `va`. This doesn't take a reference
+/// on the frame, the caller is responsible for that.
+pub fn map_page_to(pt_root: u64, va: u64, pa: u64, flags: PTEFlags) -> bool {
+	if !defs::is_aligned_4k(va) || !defs::is_aligned_4k(pa) {
+		println!("not aligned");
+		return false;
+	}
+	let Some(pte) = walk_create(pt_root, va, flags) else {
+		return false;
+	};
+	if !pte.is_unused() {
+		panic!("PTE already taken: {:#X}", pte.entry);
+	}
+	pte.set(pa, flags);
+	invlpg_root(pt_root, va);
+	return true;
+}
+
+/// remove the mapping at `va` and return the physical frame it was mapped to.
+/// The frame itself is not freed.
+pub fn unmap_page(pt_root: u64, va: u64) -> Option<u64> {
+	let pte = get_pte(pt_root, va)?;
+	if !pte.flags().contains(PTEFlags::PRESENT) {
+		return None;
+	}
+	let pa = pte.addr();
+	pte.set_unused();
+	invlpg_root(pt_root, va);
+	Some(pa)
+}
+
+/// walk the page table, create missing tables and return the leaf PTE of `va`.
+/// Intermediate entries are always writable (and user accessible if `flags`
+/// has USER), so that the effective permissions are decided by the leaf.
+fn walk_create<'a>(
+	pt_root: u64,
+	va: u64,
+	flags: PTEFlags,
+) -> Option<&'a mut PTE> {
+	let tbl_flags =
+		PTEFlags::PRESENT | PTEFlags::WRITABLE | (flags & PTEFlags::USER);
+	let idx = [
+		pagetable::p4idx(va),
+		pagetable::p3idx(va),
+		pagetable::p2idx(va),
+	];
+	let mut tbl = pt_root as *mut Pagetable;
+	for i in idx {
+		let ent = unsafe { &mut (*tbl).entries[i as usize] };
+		if ent.is_unused() {
+			let new = allocate_4k_zeroed(FrameOwner::PAGETABLE);
+			if new == 0 {
+				return None;
+			}
+			ent.set(defs::V2P(new).unwrap(), tbl_flags);
+		} else if ent.flags().contains(PTEFlags::HUGE_PAGE) {
+			println!("{:#X} is covered by a huge page", va);
+			return None;
 		} else {
-			l3_tbl = defs::P2V(l4_ent.addr()).unwrap() as *mut Pagetable;
+			ent.entry |= tbl_flags.bits();
 		}
-		let l3_ent = &mut (*l3_tbl).entries[l3idx];
-		let l2_tbl: *mut Pagetable;
-		if l3_ent.is_unused() || require_new {
-			l2_tbl = allocate_4k_zeroed() as *mut Pagetable;
-			l3_ent.entry = defs::V2P(l2_tbl as u64).unwrap() | flags;
-			require_new = true
-		} else {
-			l2_tbl = defs::P2V(l3_ent.addr()).unwrap() as *mut Pagetable;
+		tbl = P2V(ent.addr()).unwrap() as *mut Pagetable;
+	}
+	#[cfg(feature = "kpti")]
+	kpti::sync_pml4(pt_root, idx[0] as usize);
+	let l1idx = pagetable::p1idx(va) as usize;
+	Some(unsafe { &mut (*tbl).entries[l1idx] })
+}
+
+/// walk the page table without creating missing tables. Returns the leaf entry
+/// mapping `va`, which is either a 4K PTE or a huge page entry. The returned
+/// entry may be non-present.
+pub fn get_pte<'a>(pt_root: u64, va: u64) -> Option<&'a mut PTE> {
+	let idx = [
+		pagetable::p4idx(va),
+		pagetable::p3idx(va),
+		pagetable::p2idx(va),
+		pagetable::p1idx(va),
+	];
+	let mut tbl = pt_root as *mut Pagetable;
+	for (lv, i) in idx.iter().enumerate() {
+		let ent = unsafe { &mut (*tbl).entries[*i as usize] };
+		if lv == 3 || ent.flags().contains(PTEFlags::HUGE_PAGE) {
+			return Some(ent);
 		}
-		let l2_ent = &mut (*l2_tbl).entries[l2idx];
-		let l1_tbl: *mut Pagetable;
-		if l2_ent.is_unused() || require_new {
-			l1_tbl = allocate_4k_zeroed() as *mut Pagetable;
-			l2_ent.entry = defs::V2P(l1_tbl as u64).unwrap() | flags;
-			require_new = true
-		} else {
-			l1_tbl = defs::P2V(l2_ent.addr()).unwrap() as *mut Pagetable;
+		if !ent.flags().contains(PTEFlags::PRESENT) {
+			return None;
 		}
-		let pte = &mut (*l1_tbl).entries[l1idx];
-		if pte.is_unused() || require_new {
-			let page = allocate_4k_zeroed();
-			pte.entry = defs::V2P(page).unwrap() | flags;
-		} else {
-			// TODO we need to free this frame
-			panic!("PTE already taken: {:#X}", pte.entry);
+		tbl = P2V(ent.addr()).unwrap() as *mut Pagetable;
+	}
+	unreachable!();
+}
+
+/// make the physical 2M page `pa` uncached in the kernel half. The identical
+/// mapping and the kernel image window both map all of the physical memory
+/// with write back 1G pages, the ones around `pa` are split into 2M pages. The
+/// caller must flush the tlb and the caches before relying on it.
+pub fn uncache_kernel_2m(pa: u64) -> bool {
+	debug_assert!(pa & (2 * Mem::M - 1) == 0);
+	for va in [P2V(pa).unwrap(), Mem::KERNEL_OFFSET + pa] {
+		let Some(pde) = split_kernel_1g(va) else {
+			return false;
+		};
+		// PAT index 3 (UC), the PAT bit of a 2M entry (bit 12) stays 0
+		pde.set(pde.addr(), pde.flags() | PTEFlags::NC | PTEFlags::WT);
+	}
+	true
+}
+
+/// the 2M entry that maps the kernel address `va`. A 1G page there is split
+/// into 2M pages with the same flags first.
+fn split_kernel_1g<'a>(va: u64) -> Option<&'a mut PTE> {
+	let pml4 = unsafe { &*(kernel_root() as *const Pagetable) };
+	let pml4e = &pml4.entries[pagetable::p4idx(va) as usize];
+	let pdp = P2V(pml4e.addr())? as *mut Pagetable;
+	let pdpe = unsafe { &mut (*pdp).entries[pagetable::p3idx(va) as usize] };
+	let f = pdpe.flags();
+	if !f.contains(PTEFlags::PRESENT) {
+		return None;
+	}
+	if f.contains(PTEFlags::HUGE_PAGE) {
+		let pd = allocate_4k_zeroed(FrameOwner::PAGETABLE);
+		if pd == 0 {
+			return None;
 		}
-		// flush tlb
-		asm!("invlpg [{0}]", in(reg) va);
+		let tbl = unsafe { &mut *(pd as *mut Pagetable) };
+		let base = pdpe.addr();
+		for (i, e) in tbl.entries.iter_mut().enumerate() {
+			e.set(base + i as u64 * 2 * Mem::M, f);
+		}
+		// a single write: the same mapping, now in 2M pages
+		pdpe.set(defs::V2P(pd)?, PTEFlags::PRESENT | PTEFlags::WRITABLE);
 	}
-	return true;
+	let pd = P2V(pdpe.addr())? as *mut Pagetable;
+	Some(unsafe { &mut (*pd).entries[pagetable::p2idx(va) as usize] })
 }
@@ -1,18 +1,66 @@
+//! page fault handling: the fault is classified against the VMAs of the current
+//! task and resolved by demand paging, swap-in, copy-on-write or stack growth.
+//! A bad
+//! access kills the offending task, a fault in the kernel is fatal (oops).
 use crate::arch::x86_64::arch_regs::TrapFrame;
+use crate::arch::x86_64::interrupt::interrupt_enable;
+use crate::arch::x86_64::paging::{get_pte, get_root, map_page};
+use crate::arch::x86_64::paging::{vma_pte_flags, PTEFlags};
+use crate::defs::*;
 use crate::io::*;
+use crate::mm::frame::{FrameOwner, FRAME_TABLE};
+use crate::mm::reclaim::{lru_add, swap_in};
+use crate::mm::vmm::{VMArea, VMFlags, VMMan, VMPerms, VMType};
+use crate::mm::{allocate_4k, free_4k, invlpg};
+use crate::proc::sync::IS_L2_AVAILABLE;
+use crate::proc::task::{Task, EXIT_KILLED};
+use bitflags::bitflags;
 use core::arch::asm;
+use core::ptr;
 
-/// handle page fault: for now we only check if the faulting addr is within
-/// kernel heap range.
-/// TODO: improve this later
+bitflags! {
+	/// error code pushed by the cpu on page fault
+	#[derive(Copy, Clone, Debug)]
+	pub struct PFErr: u64 {
+		/// 0: non-present page; 1: protection violation
+		const PRESENT  = 1 << 0;
+		const WRITE    = 1 << 1;
+		/// the access was made in ring 3
+		const USER     = 1 << 2;
+		/// a reserved bit is set in some paging structure
+		const RESERVED = 1 << 3;
+		const IFETCH   = 1 << 4;
+	}
+}
+
+/// handle page fault: resolve faults in user address range wrt. the VMAs of
+/// the current task. This is called with interrupts disabled.
 pub fn page_fault_handler(frame: &mut TrapFrame, fault_addr: u64) {
-	let err_code = frame.err_code;
-	sprintln!("pagefault @ {:#X}, err {:#X?}", fault_addr, err_code);
-	sprintln!("{:#X?}", frame);
-	unsafe { asm!("hlt") };
+	let err = PFErr::from_bits_truncate(frame.err_code);
+	if err.contains(PFErr::RESERVED) {
+		oops(frame, fault_addr, err, "corrupted paging structure");
+	}
+	if fault_addr >= Mem::USER_END && !is_user_context(frame, err) {
+		oops(frame, fault_addr, err, "bad kernel address");
+	}
+	let Some(task) = Task::current() else {
+		oops(frame, fault_addr, err, "user address without task");
+	};
+	let reason = if fault_addr >= Mem::USER_END {
+		"bad kernel address"
+	} else {
+		match resolve(&mut task.mm, fault_addr, err) {
+			Ok(()) => return,
+			Err(r) => r,
+		}
+	};
+	if is_user_context(frame, err) {
+		unsafe { kill_current(task, frame, fault_addr, err, reason) };
+	}
+	oops(frame, fault_addr, err, reason);
 }
 
-/// for x86_64, return the CR3 register.
+/// for x86_64, return the CR2 register.
 #[inline]
 pub fn get_fault_addr() -> u64 {
 	let cr2: u64;
@@ -21,3 +69,192 @@ pub fn get_fault_addr() -> u64 {
 	}
 	cr2
 }
+
+/// the access was made by the user program (in ring 3)
+fn is_user_context(frame: &TrapFrame, err: PFErr) -> bool {
+	frame.from_user() || err.contains(PFErr::USER)
+}
+
+fn resolve(mm: &mut VMMan, va: u64, err: PFErr) -> Result<(), &'static str> {
+	let pt_root = get_root();
+	if mm.find(va).is_none() {
+		grow_stack(mm, va)?;
+	}
+	let vma = mm.find(va).unwrap();
+	if vma.user_perms.is_empty() {
+		return Err("access to kernel vma");
+	}
+	if err.contains(PFErr::WRITE) && !vma.user_perms.contains(VMPerms::W) {
+		return Err("write to read-only vma");
+	}
+	if err.contains(PFErr::IFETCH) && !vma.user_perms.contains(VMPerms::X) {
+		return Err("instruction fetch from non-executable vma");
+	}
+	if !err.contains(PFErr::PRESENT) {
+		let page = rounddown_4k(va);
+		if let Some(slot) = get_pte(pt_root, page).and_then(|p| p.swap_slot()) {
+			let owner = backing_owner(vma)?;
+			return swap_in(pt_root, page, slot, vma_pte_flags(vma), owner);
+		}
+		return demand_page(pt_root, vma, page);
+	}
+	if err.contains(PFErr::WRITE) {
+		return do_cow(pt_root, rounddown_4k(va));
+	}
+	return Err("protection violation");
+}
+
+/// owner of the frames populated on demand in the vma
+fn backing_owner(vma: &VMArea) -> Result<FrameOwner, &'static str> {
+	match vma.backing {
+		VMType::ANOM => Ok(FrameOwner::ANON),
+		VMType::FILE(_) => Ok(FrameOwner::FILE),
+		VMType::NONE => Err("device vma not mapped"),
+		VMType::SHM(_) => Err("shm vma not mapped"),
+	}
+}
+
+/// populate a non-present page in the vma with a fresh frame. For file backed
+/// vmas, the frame is filled with the file content.
+fn demand_page(
+	pt_root: u64,
+	vma: &VMArea,
+	page: u64,
+) -> Result<(), &'static str> {
+	let owner = backing_owner(vma)?;
+	if !map_page(pt_root, page, vma_pte_flags(vma), owner) {
+		return Err("out of memory");
+	}
+	let pa = get_pte(pt_root, page).unwrap().addr();
+	if let VMType::FILE(f) = vma.backing {
+		fill_from_file(P2V(pa).unwrap(), page, vma.vm_range.start, f);
+	}
+	lru_add(pa, pt_root, page);
+	Ok(())
+}
+
+/// copy the part of the file (mapped at `file_va`) that falls into the page
+/// `page` into the frame at kernel address `dst`.
+pub fn fill_from_file(dst: u64, page: u64, file_va: u64, f: &[u8]) {
+	let start = u64::max(page, file_va);
+	let end = u64::min(page + Mem::PAGE_SIZE, file_va + f.len() as u64);
+	if start >= end {
+		return;
+	}
+	unsafe {
+		ptr::copy_nonoverlapping(
+			&f[(start - file_va) as usize] as *const u8,
+			(dst + (start - page)) as *mut u8,
+			(end - start) as usize,
+		);
+	}
+}
+
+/// write fault on a present page: break the sharing if the page is COW. The
+/// last user of a COW frame takes it over without copying, frames we don't own
+/// (e.g. the ramfs) are always copied.
+fn do_cow(pt_root: u64, page: u64) -> Result<(), &'static str> {
+	let pte = get_pte(pt_root, page).ok_or("no pte for present page")?;
+	if !pte.flags().contains(PTEFlags::COW) {
+		return Err("write to read-only page");
+	}
+	let pa = pte.addr();
+	let flags = (pte.flags() - PTEFlags::COW) | PTEFlags::WRITABLE;
+	let owned = FRAME_TABLE.lock().get(pa).copied().filter(|d| {
+		d.refcount != 0
+			&& matches!(d.owner, FrameOwner::ANON | FrameOwner::FILE)
+	});
+	if owned.is_some_and(|d| d.refcount == 1) {
+		pte.set(pa, flags);
+		invlpg(page);
+		return Ok(());
+	}
+	let owner = owned.map_or(FrameOwner::FILE, |d| d.owner);
+	let new = allocate_4k(owner);
+	if new == 0 {
+		return Err("out of memory");
+	}
+	unsafe {
+		ptr::copy_nonoverlapping(
+			P2V(pa).unwrap() as *const u8,
+			new as *mut u8,
+			Mem::PAGE_SIZE as usize,
+		);
+	}
+	pte.set(V2P(new).unwrap(), flags);
+	invlpg(page);
+	lru_add(V2P(new).unwrap(), pt_root, page);
+	if owned.is_some() {
+		unsafe { free_4k(P2V(pa).unwrap()) };
+	}
+	Ok(())
+}
+
+/// extend the nearest GROWSDOWN vma above `va` to cover it.
+fn grow_stack(mm: &mut VMMan, va: u64) -> Result<(), &'static str> {
+	let page = rounddown_4k(va);
+	let stack = mm
+		.vmas
+		.iter()
+		.enumerate()
+		.filter(|(_, v)| {
+			v.flags.contains(VMFlags::GROWSDOWN) && v.vm_range.start > va
+		})
+		.min_by_key(|(_, v)| v.vm_range.start)
+		.map(|(i, _)| i)
+		.ok_or("no vma")?;
+	let (start, end) = {
+		let r = &mm.vmas[stack].vm_range;
+		(r.start, r.end)
+	};
+	if end - page > Mem::USER_STACK_MAX {
+		return Err("no vma (stack limit exceeded)");
+	}
+	if mm.overlaps(&(page..start)) {
+		return Err("no vma (stack collides with other vma)");
+	}
+	mm.vmas[stack].vm_range.start = page;
+	Ok(())
+}
+
+/// terminate the current task from the fault context with [EXIT_KILLED]. We
+/// never return to the faulting context: the task leaves through [Task::exit]
+/// like any other, with interrupts enabled as in task code.
+unsafe fn kill_current(
+	task: &mut Task,
+	frame: &TrapFrame,
+	addr: u64,
+	err: PFErr,
+	reason: &str,
+) -> ! {
+	if !IS_L2_AVAILABLE() {
+		oops(frame, addr, err, "bad user access in epilogue");
+	}
+	let rip = frame.rip;
+	println!(
+		"[PID {}] killed: {} @ {:#X} [{:?}] rip {:#X}",
+		task.pid, reason, addr, err, rip
+	);
+	interrupt_enable();
+	task.exit(EXIT_KILLED);
+}
+
+/// report a fatal kernel page fault and halt
+fn oops(frame: &TrapFrame, addr: u64, err: PFErr, reason: &str) -> ! {
+	let (rip, rsp) = (frame.rip, frame.rsp);
+	sprintln!("---- KERNEL OOPS: {} ----", reason);
+	sprintln!("page fault @ {:#X} [{:?}]", addr, err);
+	sprintln!("rip {:#X}, rsp {:#X}", rip, rsp);
+	match Task::current() {
+		Some(t) => {
+			sprintln!("task PID {}", t.pid);
+			match t.mm.vmas.iter().find(|v| v.vm_range.contains(&addr)) {
+				Some(vma) => sprintln!("vma {:?}", vma),
+				None => sprintln!("vma: none"),
+			}
+		}
+		None => sprintln!("task: none"),
+	}
+	sprintln!("{:#X?}", frame);
+	panic!("kernel oops: {} @ {:#X}, rip {:#X}", reason, addr, rip);
+}
@@ -0,0 +1,168 @@
+//! kernel page table isolation (feature `kpti`). Every task's page table root
+//! gets a user twin that shares the user half (pml4 entries 0~255) but maps
+//! only a minimal set of kernel pages:
+//! - the entry trampoline: interrupt stubs, idt and [KptiVars]
+//! - the gdt and the tss
+//! - the top page of the task's kernel stack (the cpu pushes the trap frame
+//!   there)
+//!
+//! The user twin is loaded on the way to ring 3 (`enter_user` and the exit
+//! path of the interrupt stubs in asm/vectors.s, `KPTI` is defined for this
+//! feature by build.rs), the stubs switch to the kernel page table on entry if
+//! the user one is active. With PCID both tables are tagged (the user one with
+//! [USER_PCID_BIT] on top of the task's ASID) so that the switch doesn't flush
+//! the tlb; the user tlb entries are only flushed on the return to the user
+//! when a mapping has changed.
+use crate::arch::x86_64::gdt;
+use crate::arch::x86_64::paging::pagetable::*;
+use crate::arch::x86_64::paging::pcid;
+use crate::arch::x86_64::paging::{free_subtree, get_pte, map_page_to};
+use crate::defs::*;
+use crate::mm::frame::FrameOwner;
+use crate::mm::{allocate_4k_zeroed, free_4k};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// pcid bit that tells the user twin from the kernel table
+pub const USER_PCID_BIT: u64 = 0x800;
+
+/// mirror of `kpti_vars` in asm/vectors.s
+#[repr(C)]
+pub struct KptiVars {
+	pub user_cr3: u64,
+	pub kernel_cr3: u64,
+	pub noflush: u64,
+	pub flush_user: u64,
+}
+
+extern "C" {
+	fn kpti_vars();
+}
+
+/// (kernel root, user root), both as virtual addresses
+static USER_ROOTS: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+
+#[inline]
+fn vars() -> &'static mut KptiVars {
+	unsafe { &mut *(kpti_vars as *const () as *mut KptiVars) }
+}
+
+/// cr3 value to switch to the user page table
+pub fn user_cr3() -> u64 { vars().user_cr3 }
+
+/// allocate the user twin of the page table `kroot`, which belongs to the task
+/// on the kernel stack `kstack`
+pub fn new_user_root(kroot: u64, kstack: u64) -> Option<u64> {
+	let uroot = allocate_4k_zeroed(FrameOwner::PAGETABLE);
+	if uroot == 0 {
+		return None;
+	}
+	let (ktbl, utbl) = unsafe {
+		(
+			&*(kroot as *const Pagetable),
+			&mut *(uroot as *mut Pagetable),
+		)
+	};
+	for i in 0..256 {
+		utbl.entries[i] = ktbl.entries[i].clone();
+	}
+	let flags = PTEFlags::PRESENT | PTEFlags::WRITABLE;
+	let entry = ExternSyms::___ENTRY_START__ as *const () as u64;
+	let entry_end = ExternSyms::___ENTRY_END__ as *const () as u64;
+	let mut va = entry;
+	while va < entry_end {
+		map_shared(uroot, va, K2P(va)?, flags)?;
+		va += Mem::PAGE_SIZE;
+	}
+	let gdt = rounddown_4k(gdt::gdt_addr());
+	map_shared(uroot, gdt, V2P(gdt)?, flags)?;
+	let tss = rounddown_4k(gdt::tss_addr());
+	map_shared(uroot, tss, K2P(tss)?, flags)?;
+	let top = kstack + Mem::KERNEL_STACK_SIZE - Mem::PAGE_SIZE;
+	map_shared(uroot, top, V2P(top)?, flags)?;
+	let mut roots = USER_ROOTS.lock();
+	roots.push((kroot, uroot));
+	Some(uroot)
+}
+
+/// free the user twin of `kroot`. The user half belongs to `kroot`, the kernel
+/// half only maps pages that are not ours, so only the tables go.
+/// unsafe: the twin must not be in use.
+pub unsafe fn free_user_root(kroot: u64) {
+	let mut roots = USER_ROOTS.lock();
+	let Some(i) = roots.iter().position(|(k, _)| *k == kroot) else {
+		return;
+	};
+	let (_, uroot) = roots.swap_remove(i);
+	drop(roots);
+	let utbl = &mut *(uroot as *mut Pagetable);
+	for ent in utbl.entries[256..].iter_mut() {
+		free_subtree(uroot, ent, 3, false);
+	}
+	free_4k(uroot);
+}
+
+/// map a kernel page into the user root, unless it's already there (e.g. the
+/// gdt and the tss could share a page)
+fn map_shared(uroot: u64, va: u64, pa: u64, flags: PTEFlags) -> Option<()> {
+	if get_pte(uroot, va).is_some_and(|p| p.flags().contains(PTEFlags::PRESENT))
+	{
+		return Some(());
+	}
+	map_page_to(uroot, va, pa, flags).then_some(())
+}
+
+/// propagate a change of the pml4 entry `idx` of `kroot` to its user twin.
+pub fn sync_pml4(kroot: u64, idx: usize) {
+	if idx >= 256 {
+		return;
+	}
+	let roots = USER_ROOTS.lock();
+	let Some((_, uroot)) = roots.iter().find(|(k, _)| *k == kroot) else {
+		return;
+	};
+	unsafe {
+		let ktbl = &*(kroot as *const Pagetable);
+		let utbl = &mut *(*uroot as *mut Pagetable);
+		utbl.entries[idx] = ktbl.entries[idx].clone();
+	}
+}
+
+/// point the interrupt stubs to the page tables of the task we are switching
+/// to. Called from [pcid::switch_to] with interrupts disabled.
+pub fn switch_to(kroot: u64, asid: u16) {
+	let v = vars();
+	// a flush of the previous user page table is still pending
+	let prev = (v.user_cr3 & Mem::PAGE_MASK) as u16;
+	if v.flush_user != 0 && prev != 0 {
+		pcid::mark_stale(prev);
+	}
+	let uroot = USER_ROOTS
+		.lock()
+		.iter()
+		.find(|(k, _)| *k == kroot)
+		.map(|(_, u)| *u);
+	let Some(uroot) = uroot else {
+		// no user twin (e.g. the boot page table): never switch
+		v.user_cr3 = 0;
+		v.flush_user = 0;
+		return;
+	};
+	v.kernel_cr3 = V2P(kroot).unwrap();
+	v.user_cr3 = V2P(uroot).unwrap();
+	if pcid::enabled() && asid != 0 {
+		let uasid = asid | USER_PCID_BIT as u16;
+		v.kernel_cr3 |= asid as u64;
+		v.user_cr3 |= uasid as u64;
+		v.noflush = pcid::CR3_NOFLUSH;
+		v.flush_user = pcid::take_stale(uasid) as u64;
+	} else {
+		v.noflush = 0;
+		v.flush_user = 1;
+	}
+}
+
+/// user mappings have changed: the user tlb entries are flushed on the next
+/// return to the user page table.
+#[inline]
+pub fn flush_user() { vars().flush_user = 1; }
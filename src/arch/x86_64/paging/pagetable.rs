@@ -28,9 +28,16 @@ pub struct PTEFlags:u64 {
 	const ACCESSED  = 1 << 5;
 	const DIRTY     = 1 << 6;
 	const HUGE_PAGE = 1 << 7;
+	/// in a 4K entry: bit 2 of the PAT index, bits 1 and 0 are NC and WT.
+	/// See [super::pat]
+	const PAT       = 1 << 7;
 	const GLOBAL    = 1 << 8;
-	const B9        = 1 << 9;
-	const B10       = 1 << 10;
+	/// (software) copy-on-write: the page is mapped read-only and shared,
+	/// a write fault gets a private copy.
+	const COW       = 1 << 9;
+	/// (software) in a non-present entry: the page is swapped out, the
+	/// address bits hold the swap slot.
+	const SWAP      = 1 << 10;
 	const B11       = 1 << 11;
 	// [51:12] is used for translation address
 	// [62:52] are user defined.
@@ -113,6 +120,22 @@ impl PTE {
 	pub fn set(&mut self, pa: u64, flags: PTEFlags) {
 		self.entry = pa | flags.bits();
 	}
+
+	/// make this a (non-present) swap entry for `slot`
+	#[inline]
+	pub fn set_swap(&mut self, slot: u64) {
+		self.entry = (slot << 12) | PTEFlags::SWAP.bits();
+	}
+
+	/// the swap slot if this is a swap entry
+	#[inline]
+	pub fn swap_slot(&self) -> Option<u64> {
+		let f = self.flags();
+		if f.contains(PTEFlags::PRESENT) || !f.contains(PTEFlags::SWAP) {
+			return None;
+		}
+		Some(self.addr() >> 12)
+	}
 }
 
 const ID_MASK: u64 = 0x1ff;
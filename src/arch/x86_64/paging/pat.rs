@@ -0,0 +1,66 @@
+//! page attribute table: the memory type of a 4K page is picked by the PAT
+//! index `PAT:NC:WT` in its PTE. We keep the power-on defaults for the first
+//! four entries, so that plain NC/WT mappings mean what they always meant, and
+//! make entry 4 write-combining:
+//!
+//! | index | type | PTE bits  |
+//! |-------|------|-----------|
+//! | 0     | WB   |           |
+//! | 1     | WT   | WT        |
+//! | 2     | UC-  | NC        |
+//! | 3     | UC   | NC WT     |
+//! | 4     | WC   | PAT       |
+//!
+//! Without PAT (ancient cpus) write-combining falls back to uncached.
+use crate::arch::x86_64::cpuid;
+use crate::arch::x86_64::msr::{rdmsr, wrmsr, IA32_PAT};
+use crate::arch::x86_64::paging::PTEFlags;
+use crate::mm::flush_tlb;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// memory types as encoded in the PAT msr
+const MT_WC: u64 = 0x01;
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// caching of a mapping
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+	/// write back, normal memory
+	WB,
+	/// write through
+	WT,
+	/// uncached, strongly ordered: device registers
+	UC,
+	/// write combining: framebuffers and alike
+	WC,
+}
+
+/// program the PAT, must be called before any mapping uses the PAT bit.
+pub fn init() {
+	if !cpuid::has_pat() {
+		return;
+	}
+	unsafe {
+		let mut pat = rdmsr(IA32_PAT);
+		pat &= !(0xff << 32);
+		pat |= MT_WC << 32;
+		wrmsr(IA32_PAT, pat);
+	}
+	flush_tlb();
+	PAT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn enabled() -> bool { PAT_ENABLED.load(Ordering::Relaxed) }
+
+/// PTE bits selecting the memory type `mode` in a 4K entry
+pub fn pte_flags(mode: CacheMode) -> PTEFlags {
+	match mode {
+		CacheMode::WB => PTEFlags::ZERO,
+		CacheMode::WT => PTEFlags::WT,
+		CacheMode::UC => PTEFlags::NC | PTEFlags::WT,
+		CacheMode::WC if enabled() => PTEFlags::PAT,
+		CacheMode::WC => PTEFlags::NC | PTEFlags::WT,
+	}
+}
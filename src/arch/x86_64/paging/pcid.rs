@@ -0,0 +1,185 @@
+//! process-context identifiers: every address space (page table root) gets an
+//! address space id (ASID), which is used as PCID when we switch CR3. The tlb
+//! entries of an address space then survive switching to another one, and CR3
+//! is written with the no-flush bit.
+//!
+//! A PCID must be flushed before it's used with different mappings:
+//! - ASIDs are recycled, a recycled ASID is stale until the first switch to it.
+//! - changes to the mappings of an address space that is not the current one
+//!   are either invalidated with INVPCID (if the cpu has it) or mark the ASID
+//!   stale.
+//!
+//! ASID 0 is used by the boot page table and by address spaces that didn't get
+//! an ASID (all taken); switching to ASID 0 always flushes.
+use crate::arch::x86_64::cpuid;
+#[cfg(feature = "kpti")]
+use crate::arch::x86_64::paging::kpti;
+use crate::defs::*;
+use crate::machine::interrupt::{irq_restore, irq_save};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// no-flush bit for CR3 writes
+pub const CR3_NOFLUSH: u64 = 1 << 63;
+/// ASIDs are 1 ~ MAX_ASID - 1. The upper half of the PCID space is reserved
+/// for the KPTI user page tables
+pub const MAX_ASID: u16 = 0x800;
+const NR_PCIDS: usize = 4096;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static HAS_INVPCID: AtomicBool = AtomicBool::new(false);
+/// bitmap of PCIDs that must be flushed on the next switch
+static STALE: [AtomicU64; NR_PCIDS / 64] =
+	[const { AtomicU64::new(0) }; NR_PCIDS / 64];
+
+pub static ASID_ALLOCATOR: Mutex<AsidAllocator> =
+	Mutex::new(AsidAllocator::new());
+
+pub struct AsidAllocator {
+	/// next never used ASID
+	next: u16,
+	/// recycled ASIDs
+	free: Vec<u16>,
+	/// page table root (kernel va) => ASID
+	roots: BTreeMap<u64, u16>,
+}
+
+impl AsidAllocator {
+	pub const fn new() -> Self {
+		Self {
+			next: 1,
+			free: Vec::new(),
+			roots: BTreeMap::new(),
+		}
+	}
+
+	/// assign an ASID to the page table root. Returns 0 if PCID is not enabled
+	/// or if we ran out of ASIDs.
+	pub fn alloc(&mut self, pt_root: u64) -> u16 {
+		if !enabled() {
+			return 0;
+		}
+		let asid = if let Some(a) = self.free.pop() {
+			mark_stale(a);
+			a
+		} else if self.next < MAX_ASID {
+			self.next += 1;
+			self.next - 1
+		} else {
+			return 0;
+		};
+		self.roots.insert(pt_root, asid);
+		asid
+	}
+
+	/// release the ASID of the page table root
+	pub fn free(&mut self, pt_root: u64) {
+		if let Some(asid) = self.roots.remove(&pt_root) {
+			self.free.push(asid);
+		}
+	}
+
+	pub fn asid_of(&self, pt_root: u64) -> Option<u16> {
+		self.roots.get(&pt_root).copied()
+	}
+}
+
+/// detect PCID support and enable it
+pub fn init() {
+	if !cpuid::has_pcid() {
+		return;
+	}
+	super::enable_pcid();
+	PCID_ENABLED.store(true, Ordering::Relaxed);
+	HAS_INVPCID.store(cpuid::has_invpcid(), Ordering::Relaxed);
+}
+
+#[inline]
+pub fn enabled() -> bool { PCID_ENABLED.load(Ordering::Relaxed) }
+
+#[inline]
+pub fn has_invpcid() -> bool { HAS_INVPCID.load(Ordering::Relaxed) }
+
+pub fn mark_stale(pcid: u16) {
+	let pcid = pcid as usize;
+	STALE[pcid / 64].fetch_or(1 << (pcid % 64), Ordering::Relaxed);
+}
+
+/// clear the stale mark, returns whether it was set
+pub fn take_stale(pcid: u16) -> bool {
+	let (pcid, bit) = (pcid as usize, 1 << (pcid as usize % 64));
+	STALE[pcid / 64].fetch_and(!bit, Ordering::Relaxed) & bit != 0
+}
+
+/// the address space is no longer current: flush everything on the next use.
+pub fn mark_all_stale() {
+	for w in STALE.iter() {
+		w.store(u64::MAX, Ordering::Relaxed);
+	}
+}
+
+/// load the page table root `pt_root` (kernel va) tagged with `asid`
+pub fn switch_to(pt_root: u64, asid: u16) {
+	let mut cr3 = V2P(pt_root).unwrap();
+	if enabled() && asid != 0 {
+		cr3 |= asid as u64;
+		if !take_stale(asid) {
+			cr3 |= CR3_NOFLUSH;
+		}
+	}
+	// the interrupt stubs must see the new cr3 and the kpti vars together
+	let irq = irq_save();
+	unsafe { asm!("mov cr3, {}", in(reg) cr3) };
+	#[cfg(feature = "kpti")]
+	kpti::switch_to(pt_root, asid);
+	irq_restore(irq);
+}
+
+/// invalidate the mapping of `va` in the address space `pt_root`, which is not
+/// the current one.
+pub fn invalidate(pt_root: u64, va: u64) {
+	if !enabled() {
+		// no tlb entries survive the switch to it
+		return;
+	}
+	let Some(asid) = ASID_ALLOCATOR.lock().asid_of(pt_root) else {
+		// asid 0 always flushes
+		return;
+	};
+	if has_invpcid() {
+		invpcid(InvpcidType::ADDR, asid, va);
+	} else {
+		mark_stale(asid);
+	}
+	#[cfg(feature = "kpti")]
+	mark_stale(asid | kpti::USER_PCID_BIT as u16);
+}
+
+#[repr(u64)]
+#[allow(non_camel_case_types)]
+pub enum InvpcidType {
+	/// a single address in a single PCID
+	ADDR   = 0,
+	/// all (non-global) mappings of a single PCID
+	SINGLE = 1,
+	/// everything, including global mappings
+	ALL_GLOBAL = 2,
+	/// everything except global mappings
+	ALL    = 3,
+}
+
+#[inline]
+pub fn invpcid(t: InvpcidType, pcid: u16, va: u64) {
+	let desc: [u64; 2] = [pcid as u64, va];
+	unsafe {
+		asm!(
+			"invpcid {0}, [{1}]",
+			in(reg) t as u64,
+			in(reg) &desc as *const _ as u64,
+			options(nostack, preserves_flags),
+		)
+	};
+}
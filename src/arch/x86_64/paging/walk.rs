@@ -0,0 +1,169 @@
+//! read-only page table walker: translate addresses and iterate over the
+//! present mappings of a page table. The permissions reported are the effective
+//! ones, i.e. combined over all levels: a page is writable (user accessible)
+//! only if every level allows it, and non-executable if any level says so.
+use crate::arch::x86_64::paging::pagetable::*;
+use crate::defs::*;
+use core::fmt;
+
+/// a (run of) present mapping(s)
+#[derive(Copy, Clone, Debug)]
+pub struct Mapping {
+	pub va: u64,
+	pub pa: u64,
+	/// length in bytes, a multiple of `page_size`
+	pub size: u64,
+	/// 4K, 2M or 1G
+	pub page_size: u64,
+	/// effective flags
+	pub flags: PTEFlags,
+}
+
+/// page size mapped by an entry at level `lv` (0 = pml4)
+const LV_SIZE: [u64; 4] = [512 * Mem::G, Mem::G, 2 * Mem::M, Mem::PAGE_SIZE];
+/// the bits of effective flags that come from the leaf only
+const LEAF_ONLY: PTEFlags = PTEFlags::ACCESSED
+	.union(PTEFlags::DIRTY)
+	.union(PTEFlags::GLOBAL)
+	.union(PTEFlags::COW)
+	.union(PTEFlags::WT)
+	.union(PTEFlags::NC);
+
+/// sign extend a 48 bit address
+#[inline]
+fn canonical(va: u64) -> u64 {
+	if va & (1 << 47) != 0 {
+		va | 0xffff_0000_0000_0000
+	} else {
+		va
+	}
+}
+
+/// physical address in a leaf entry. For huge pages the low bits of the address
+/// field hold the PAT bit and must be masked.
+#[inline]
+fn leaf_addr(ent: &PTE, page_size: u64) -> u64 { ent.addr() & !(page_size - 1) }
+
+/// combine the flags of an upper level entry into the effective flags
+#[inline]
+fn combine(eff: PTEFlags, ent: PTEFlags) -> PTEFlags {
+	let and = PTEFlags::WRITABLE | PTEFlags::USER;
+	(eff - and) | (eff & ent & and) | (ent & PTEFlags::NE)
+}
+
+/// translate `va` in the page table `pt_root`. Returns the mapping of the page
+/// containing `va` (with `size == page_size`) and the physical address of `va`.
+pub fn translate(pt_root: u64, va: u64) -> Option<(Mapping, u64)> {
+	let idx = [p4idx(va), p3idx(va), p2idx(va), p1idx(va)];
+	let mut tbl = pt_root as *const Pagetable;
+	let mut eff = PTEFlags::WRITABLE | PTEFlags::USER;
+	for lv in 0..4 {
+		let ent = unsafe { &(*tbl).entries[idx[lv] as usize] };
+		let f = ent.flags();
+		if !f.contains(PTEFlags::PRESENT) {
+			return None;
+		}
+		eff = combine(eff, f);
+		let leaf = lv == 3 || (lv > 0 && f.contains(PTEFlags::HUGE_PAGE));
+		if leaf {
+			let page_size = LV_SIZE[lv];
+			let m = Mapping {
+				va: va & !(page_size - 1),
+				pa: leaf_addr(ent, page_size),
+				size: page_size,
+				page_size,
+				flags: eff | PTEFlags::PRESENT | (f & LEAF_ONLY),
+			};
+			return Some((m, m.pa + (va & (page_size - 1))));
+		}
+		tbl = P2V(ent.addr())? as *const Pagetable;
+	}
+	unreachable!();
+}
+
+/// flags equal except for the accessed and dirty bits
+#[inline]
+fn same_perms(a: PTEFlags, b: PTEFlags) -> bool {
+	((a ^ b) - (PTEFlags::ACCESSED | PTEFlags::DIRTY)).is_empty()
+}
+
+/// call `f` on every present mapping in the page table. Contiguous pages (in
+/// both virtual and physical address space) with the same page size and
+/// effective flags are coalesced into one [Mapping], the accessed and dirty
+/// bits are those of the first page in the run.
+pub fn for_each_mapping(pt_root: u64, mut f: impl FnMut(&Mapping)) {
+	let mut run: Option<Mapping> = None;
+	let mut visit = |m: Mapping| match run.as_mut() {
+		Some(r)
+			if r.va + r.size == m.va
+				&& r.pa + r.size == m.pa
+				&& r.page_size == m.page_size
+				&& same_perms(r.flags, m.flags) =>
+		{
+			r.size += m.size
+		}
+		_ => {
+			if let Some(r) = run.replace(m) {
+				f(&r);
+			}
+		}
+	};
+	let eff = PTEFlags::WRITABLE | PTEFlags::USER;
+	walk_table(pt_root, 0, 0, eff, &mut visit);
+	if let Some(r) = run {
+		f(&r);
+	}
+}
+
+fn walk_table(
+	tbl: u64,
+	lv: usize,
+	base: u64,
+	eff: PTEFlags,
+	visit: &mut impl FnMut(Mapping),
+) {
+	let tbl = unsafe { &*(tbl as *const Pagetable) };
+	for (i, ent) in tbl.iter().enumerate() {
+		let f = ent.flags();
+		if !f.contains(PTEFlags::PRESENT) {
+			continue;
+		}
+		let va = base + i as u64 * LV_SIZE[lv];
+		let eff = combine(eff, f);
+		if lv == 3 || (lv > 0 && f.contains(PTEFlags::HUGE_PAGE)) {
+			visit(Mapping {
+				va: canonical(va),
+				pa: leaf_addr(ent, LV_SIZE[lv]),
+				size: LV_SIZE[lv],
+				page_size: LV_SIZE[lv],
+				flags: eff | PTEFlags::PRESENT | (f & LEAF_ONLY),
+			});
+		} else if let Some(next) = P2V(ent.addr()) {
+			walk_table(next, lv + 1, va, eff, visit);
+		}
+	}
+}
+
+impl fmt::Display for Mapping {
+	/// e.g. `0000000000400000-0000000000402000 -> 1F2000 r-xu 4K`
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let fl = self.flags;
+		write!(
+			f,
+			"{:016X}-{:016X} -> {:X} r{}{}{}{}{} {}",
+			self.va,
+			self.va.wrapping_add(self.size),
+			self.pa,
+			if fl.contains(PTEFlags::WRITABLE) { "w" } else { "-" },
+			if fl.contains(PTEFlags::NE) { "-" } else { "x" },
+			if fl.contains(PTEFlags::USER) { "u" } else { "-" },
+			if fl.contains(PTEFlags::GLOBAL) { "g" } else { "" },
+			if fl.contains(PTEFlags::COW) { " cow" } else { "" },
+			match self.page_size {
+				Mem::PAGE_SIZE => "4K",
+				s if s == 2 * Mem::M => "2M",
+				_ => "1G",
+			}
+		)
+	}
+}
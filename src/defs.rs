@@ -43,14 +43,23 @@ pub mod Mem {
 	// (64 GiB)
 	pub const KERNEL_HEAP_START: u64 = 0xffff_8030_0000_0000;
 	pub const KERNEL_HEAP_END: u64 = 0xffff_8040_0000_0000;
+	// mmio mappings (ioremap): 0xffff_8040_0000_0000 ~ 0xffff_804f_ffff_ffff
+	pub const IOREMAP_START: u64 = 0xffff_8040_0000_0000;
+	pub const IOREMAP_END: u64 = 0xffff_8050_0000_0000;
 	// unlike the initial "thread" that has 64K stack, new tasks have 4 pages of
 	// kernel stack.
 	pub const KERNEL_STACK_SIZE: u64 = 0x4000;
 	pub const KERNEL_STACK_MASK: u64 = KERNEL_STACK_SIZE - 1;
 	pub const KERNEL_STACK_TASK_MAGIC: u64 = 0x1A2B3C4D5E6F6969;
 	// user (psuedo)
+	pub const USER_END: u64 = 0x0000_8000_0000_0000;
 	pub const USER_STACK_START: u64 = 0x0000_7000_0000_0000;
 	pub const USER_STACK_SIZE: u64 = 8 * M;
+	// the user stack may grow on page faults up to this size
+	pub const USER_STACK_MAX: u64 = 64 * M;
+	// range for mappings (e.g. shm) where the address is picked by the kernel
+	pub const USER_MMAP_START: u64 = 0x0000_4000_0000_0000;
+	pub const USER_MMAP_END: u64 = 0x0000_6000_0000_0000;
 }
 
 // TODO use a consistent naming convention for extern symbols
@@ -62,6 +71,9 @@ pub mod ExternSyms {
 		pub fn ___BSS_END__();
 		pub fn ___RAMFS_START__();
 		pub fn ___RAMFS_END__();
+		/// the entry trampoline, see [crate::arch::x86_64::paging::kpti]
+		pub fn ___ENTRY_START__();
+		pub fn ___ENTRY_END__();
 		/// a chunk (8M) of reserved memory, optionally used by the stack based
 		/// physical frame allocator. This naive pma is deprecated, and you must not
 		/// use this symbol unless you adjust the startup code to reserve
@@ -76,8 +88,9 @@ pub mod ExternSyms {
 pub mod HWDefs {
 	/// number of entries in IDT
 	pub const IDT_CAPACITY: usize = 256;
-	/// 32 exceptions + 16 irqs from PIC = 48 valid interrupts
-	pub const IDT_VALID: usize = 48;
+	/// 32 exceptions + 16 irqs from PIC + 16 local APIC vectors = 64 valid
+	/// interrupts
+	pub const IDT_VALID: usize = 64;
 	/// size of interrupt handler wrapper routine (vector)
 	pub const VECTOR_SIZE: usize = 16;
 }
@@ -87,6 +100,8 @@ pub mod Limits {
 	// allocation
 	pub const SEM_WAIT_QUEUE_MIN_CAP: usize = 16;
 	pub const SCHED_RUN_QUEUE_MIN_CAP: usize = 24;
+	/// size of the task slot table, see [crate::proc::task::TaskId]
+	pub const MAX_TASKS: usize = 1024;
 }
 
 /// convert VA <-> PA wrt. the kernel id mapping
@@ -111,6 +126,16 @@ pub const fn P2V(pa: u64) -> Option<u64> {
 	return Some(pa + Mem::ID_MAP_START);
 }
 
+/// kernel image address (linked at [Mem::KERNEL_OFFSET]) to physical.
+#[allow(non_snake_case)]
+#[inline]
+pub const fn K2P(va: u64) -> Option<u64> {
+	if va < Mem::KERNEL_OFFSET || va >= Mem::KERNEL_OFFSET + Mem::MAX_PHY_MEM {
+		return None;
+	}
+	return Some(va - Mem::KERNEL_OFFSET);
+}
+
 /// interrut numbers. Not complete, add more when needed
 /// (see docs/interrupt.txt)
 pub struct IntNumber {}
@@ -118,5 +143,9 @@ impl IntNumber {
 	pub const PAGEFAULT: u16 = 0xe;
 	pub const TIMER: u16 = 0x20;
 	pub const KEYBOARD: u16 = 0x21;
+	pub const RTC: u16 = 0x28;
+	pub const APIC_TIMER: u16 = 0x30;
+	/// the low 4 bits must be set for older APICs
+	pub const APIC_SPURIOUS: u16 = 0x3f;
 	pub const SYSCALL: u16 = 0x80;
 }
@@ -17,6 +17,12 @@ pub fn get_archive<'a>() -> &'a [u8] {
 	ramfs
 }
 
+/// whether the address is inside the statically linked ramfs
+pub fn in_ramfs(addr: u64) -> bool {
+	let ramfs = get_archive().as_ptr_range();
+	(ramfs.start as u64..ramfs.end as u64).contains(&addr)
+}
+
 pub fn cat(f: &File) {
 	match str::from_utf8(f.file) {
 		Ok(s) => println!("{}", s),
@@ -11,6 +11,12 @@ pub use lazy::Lazy;
 pub mod kshell;
 pub use kshell::Kshell;
 
+pub mod spawn;
+pub use spawn::{spawn, JoinHandle};
+
+use crate::arch::x86_64::cpuid;
+use crate::arch::x86_64::misc::wait_for_interrupt;
+use crate::proc::sched::Scheduler;
 use crate::proc::sync::LEAVE_L2;
 
 pub trait KThread {
@@ -23,12 +29,14 @@ pub trait KThread {
 	fn get_entry() -> u64 { Self::_entry as u64 }
 }
 
+/// the idle task, installed with [crate::proc::sched::Scheduler::set_idle]
 pub struct Idle {}
 impl KThread for Idle {
 	fn entry() -> ! {
-		use core::arch::asm;
+		let mwait = cpuid::has_mwait();
 		loop {
-			unsafe { asm!("sti; hlt") };
+			Scheduler::reap();
+			wait_for_interrupt(mwait);
 		}
 	}
 }
@@ -1,10 +1,25 @@
 //! a simple shell...
+use crate::arch::x86_64::paging::get_root;
+use crate::arch::x86_64::paging::walk;
+use crate::defs::Mem;
 use crate::io::{back_space, read_key};
 use crate::kthread::KThread;
-use crate::proc::exec::exec;
-use crate::proc::task::Task;
+use crate::machine::interrupt::{irq_restore, irq_save};
+use crate::machine::rtc::DateTime;
+use crate::machine::time;
+use crate::mm;
+use crate::mm::frame::FrameOwner;
+use crate::proc::exec::spawn_exec;
+use crate::proc::pid;
+use crate::proc::sched::rt::SchedPolicy;
+use crate::proc::sched::GLOBAL_SCHEDULER;
+use crate::proc::syscall;
+use crate::proc::task::{Task, TaskId, TaskState, TaskStats};
 use crate::{fs::*, io};
+use alloc::format;
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::cmp::Reverse;
 use core::str;
 pub struct Kshell {}
 
@@ -69,6 +84,40 @@ fn handle(cmd: &str, files: &Vec<File>) {
 		"clear" => {
 			io::reset_screen();
 		}
+		"free" | "meminfo" => meminfo(),
+		"heapck" => {
+			if mm::heap_check().is_none() {
+				println!("heapck: kernel built without debug_heap");
+			}
+		}
+		"pt" => pt(tokens.get(1).copied()),
+		"nice" => nice(&tokens[1..]),
+		"chrt" => chrt(&tokens[1..]),
+		"ps" => ps(),
+		"top" => top(tokens.get(1).copied()),
+		"date" => date(),
+		"uptime" => {
+			let up = time::nsec();
+			let idle = GLOBAL_SCHEDULER.lock().idle_time();
+			println!(
+				"up {} s, idle {} s, {}% busy",
+				up / 1_000_000_000,
+				idle / 1_000_000_000,
+				up.saturating_sub(idle) * 100 / up.max(1)
+			);
+		}
+		"shm" => {
+			for (id, seg) in &mm::shm::SHM_REGISTRY.lock().segs {
+				println!(
+					"[{}] {} {} bytes, {} attached{}",
+					id,
+					seg.name,
+					seg.size,
+					seg.nattch,
+					if seg.unlinked { " (unlinked)" } else { "" }
+				);
+			}
+		}
 		"mem" => {
 			let mm = &Task::current().unwrap().mm;
 			for vma in &mm.vmas {
@@ -76,11 +125,272 @@ fn handle(cmd: &str, files: &Vec<File>) {
 			}
 		}
 		whatever => {
-			exec(whatever);
-			let mm = &Task::current().unwrap().mm;
-			for vma in &mm.vmas {
-				println!("{:#?}", vma);
+			// user programs never return a value, only an exit code
+			if let Err(code) = spawn_exec(whatever).join() {
+				println!("{} exited with {}", whatever, code);
+			}
+		}
+	}
+}
+
+fn meminfo() {
+	let st = mm::stats();
+	println!(
+		"frames: {} total, {} free ({} KiB)",
+		st.total_frames,
+		st.free_frames,
+		st.free_frames * Mem::PAGE_SIZE / Mem::K
+	);
+	println!(
+		"heap: {} KiB used / {} KiB",
+		st.heap_used / Mem::K,
+		st.heap_size / Mem::K
+	);
+	println!(
+		"reclaim: {} pages on lru, {} swapped ({} KiB compressed)",
+		st.lru_pages,
+		st.swap_pages,
+		st.swap_bytes / Mem::K
+	);
+	for o in FrameOwner::ALL {
+		if o == FrameOwner::NONE {
+			continue;
+		}
+		println!("  {:<12} {} frames", o.name(), st.owned[o as usize]);
+	}
+	for t in task_infos() {
+		println!("  [PID {}] rss {} pages", t.pid, t.rss);
+	}
+}
+
+/// what the listings show of a task. It's copied with interrupts disabled, the
+/// task may be gone by the time it's printed.
+struct TaskInfo {
+	pid: u32,
+	name: String,
+	state: TaskState,
+	nice: i8,
+	prio: u8,
+	vruntime: u64,
+	policy: SchedPolicy,
+	dl_misses: u64,
+	stats: TaskStats,
+	rss: u64,
+}
+
+fn task_info(tid: TaskId) -> Option<TaskInfo> {
+	let r = irq_save();
+	let info = tid.get().map(|t| TaskInfo {
+		pid: t.pid,
+		name: t.name.clone(),
+		state: t.state,
+		nice: t.nice,
+		prio: t.se.prio,
+		vruntime: t.se.vruntime,
+		policy: t.se.policy,
+		dl_misses: t.se.dl.misses,
+		stats: t.stats.clone(),
+		rss: t.mm.rss(t.pt_root),
+	});
+	irq_restore(r);
+	info
+}
+
+/// all tasks in pid order
+fn task_infos() -> Vec<TaskInfo> {
+	pid::tasks().into_iter().filter_map(task_info).collect()
+}
+
+/// scheduling class and priority of a task, for listings
+fn prio_str(t: &TaskInfo) -> String {
+	match t.policy {
+		SchedPolicy::Normal => format!("{}", t.nice),
+		SchedPolicy::Fifo(p) => format!("FF{}", p),
+		SchedPolicy::RoundRobin(p) => format!("RR{}", p),
+		SchedPolicy::Deadline => String::from("DL"),
+	}
+}
+
+/// `ps`: list all tasks
+fn ps() {
+	println!(
+		"{:>5} {:<12} {:<5} {:>4} {:>10} {:>6} {:>6}",
+		"PID", "NAME", "STATE", "PRIO", "TIME(ms)", "VCSW", "IVCSW"
+	);
+	for t in task_infos() {
+		println!(
+			"{:>5} {:<12} {:<5} {:>4} {:>10} {:>6} {:>6}",
+			t.pid,
+			t.name,
+			format!("{:?}", t.state),
+			prio_str(&t),
+			t.stats.runtime / 1_000_000,
+			t.stats.nvcsw,
+			t.stats.nivcsw
+		);
+	}
+}
+
+/// `top [n]`: show the cpu usage of the tasks every second, n times
+/// (default 10)
+fn top(arg: Option<&str>) {
+	const ROUNDS: u32 = 10;
+	let rounds = match arg.map(|a| a.parse::<u32>()) {
+		None => ROUNDS,
+		Some(Ok(n)) => n,
+		Some(Err(_)) => {
+			println!("usage: top [<rounds>]");
+			return;
+		}
+	};
+	let me = Task::current().unwrap();
+	let mut last = task_infos();
+	let mut last_ns = time::nsec();
+	for _ in 0..rounds {
+		me.nanosleep(1_000_000_000);
+		let now = time::nsec();
+		let curr = task_infos();
+		let interval = now.saturating_sub(last_ns).max(1);
+		let idle = GLOBAL_SCHEDULER.lock().idle_time();
+		// (cpu time in the interval, task)
+		let mut usage: Vec<(u64, &TaskInfo)> = curr
+			.iter()
+			.map(|t| {
+				let prev = last
+					.iter()
+					.find(|l| l.pid == t.pid)
+					.map_or(0, |l| l.stats.runtime);
+				(t.stats.runtime.saturating_sub(prev), t)
+			})
+			.collect();
+		usage.sort_unstable_by_key(|(ns, t)| Reverse((*ns, t.pid)));
+		io::reset_screen();
+		println!(
+			"top - up {} s, {}% busy overall, {} tasks",
+			now / 1_000_000_000,
+			now.saturating_sub(idle) * 100 / now.max(1),
+			curr.len()
+		);
+		println!(
+			"{:>5} {:<12} {:<5} {:>4} {:>5} {:>10} {:>10}",
+			"PID", "NAME", "STATE", "PRIO", "%CPU", "TIME(ms)", "WAKEUP(us)"
+		);
+		for (ns, t) in usage {
+			println!(
+				"{:>5} {:<12} {:<5} {:>4} {:>5} {:>10} {:>10}",
+				t.pid,
+				t.name,
+				format!("{:?}", t.state),
+				prio_str(t),
+				ns * 100 / interval,
+				t.stats.runtime / 1_000_000,
+				t.stats.wakeup_avg() / 1_000
+			);
+		}
+		last = curr;
+		last_ns = now;
+	}
+}
+
+/// `nice`: list the priorities and policies of all tasks
+/// `nice <pid> <value>`: set the nice value of a task (through the syscall)
+fn nice(args: &[&str]) {
+	if args.is_empty() {
+		for t in task_infos() {
+			println!(
+				"  [PID {}] nice {:>3} prio {} vruntime {} ms {:?}",
+				t.pid,
+				t.nice,
+				t.prio,
+				t.vruntime / 1_000_000,
+				t.policy
+			);
+			if t.policy == SchedPolicy::Deadline {
+				println!("    deadline misses: {}", t.dl_misses);
 			}
 		}
+		return;
+	}
+	let (Some(Ok(pid)), Some(Ok(val))) = (
+		args.first().map(|a| a.parse::<u32>()),
+		args.get(1).map(|a| a.parse::<i64>()),
+	) else {
+		println!("usage: nice [<pid> <value>]");
+		return;
+	};
+	let ret = syscall::syscall3(syscall::nr::NICE, pid as u64, val as u64, 0);
+	match ret {
+		syscall::ESRCH => println!("nice: no task {}", pid),
+		r if r < 0 => println!("nice: error {}", r),
+		old => println!("nice: PID {} {} -> {}", pid, old, val),
+	}
+}
+
+/// `chrt <pid> normal|fifo <prio>|rr <prio>|deadline <runtime> <deadline>
+/// <period>`: set the scheduling policy of a task, times in microseconds
+fn chrt(args: &[&str]) {
+	let pid = args.first().and_then(|a| a.parse::<u32>().ok());
+	let nums = args
+		.get(2..)
+		.unwrap_or(&[])
+		.iter()
+		.map(|a| a.parse::<u64>())
+		.collect::<Result<Vec<u64>, _>>()
+		.unwrap_or_default();
+	let us = |v: u64| v.saturating_mul(1000);
+	let policy = match (args.get(1).copied(), nums.as_slice()) {
+		(Some("normal"), []) => Some((syscall::SCHED_NORMAL, 0, [0; 3])),
+		(Some("fifo"), [p]) => Some((syscall::SCHED_FIFO, *p, [0; 3])),
+		(Some("rr"), [p]) => Some((syscall::SCHED_RR, *p, [0; 3])),
+		(Some("deadline"), [r, d, p]) => {
+			Some((syscall::SCHED_DEADLINE, 0, [us(*r), us(*d), us(*p)]))
+		}
+		_ => None,
+	};
+	let (Some(pid), Some((policy, prio, [runtime, deadline, period]))) =
+		(pid, policy)
+	else {
+		println!(
+			"usage: chrt <pid> normal|fifo <prio>|rr <prio>|deadline \
+			 <runtime> <deadline> <period> (us)"
+		);
+		return;
+	};
+	let ret = syscall::syscall6(
+		syscall::nr::SCHED_SETATTR,
+		[pid as u64, policy, prio, runtime, deadline, period],
+	);
+	match ret {
+		0 => println!("chrt: PID {} {}", pid, args[1..].join(" ")),
+		syscall::ESRCH => println!("chrt: no task {}", pid),
+		syscall::EBUSY => println!("chrt: rt bandwidth exceeded"),
+		r => println!("chrt: error {}", r),
+	}
+}
+
+fn date() {
+	// the syscall only takes user buffers, we are on the kernel stack
+	let secs = time::realtime_nsec() / 1_000_000_000;
+	println!("{}", DateTime::from_unix(secs));
+}
+
+/// dump the mappings of the current address space, or translate `addr`
+fn pt(addr: Option<&str>) {
+	let pt_root = get_root();
+	let Some(addr) = addr else {
+		walk::for_each_mapping(pt_root, |m| println!("{}", m));
+		return;
+	};
+	let va = match addr.strip_prefix("0x") {
+		Some(hex) => u64::from_str_radix(hex, 16),
+		None => addr.parse::<u64>(),
+	};
+	let Ok(va) = va else {
+		println!("pt: bad address {}", addr);
+		return;
+	};
+	match walk::translate(pt_root, va) {
+		Some((m, pa)) => println!("{:#X} -> {:#X}\n{}", va, pa, m),
+		None => println!("{:#X} not mapped", va),
 	}
 }
@@ -0,0 +1,127 @@
+//! kernel threads from closures: [spawn] runs a closure in a new task and
+//! returns a [JoinHandle] to wait for its result. The thread exits when the
+//! closure returns, or earlier when it's killed (e.g. on a bad memory access).
+//! [spawn_entry] does the same for a task entry that never returns, e.g. a user
+//! program.
+use crate::kthread::KThread;
+use crate::proc::sched::{Scheduler, GLOBAL_SCHEDULER};
+use crate::proc::sync::L2Sync;
+use crate::proc::task::{Task, TaskId};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// what the thread and its [JoinHandle] share
+struct Packet<T> {
+	/// the return value of the closure, or the exit code if the thread ended
+	/// before it returned
+	result: Option<Result<T, i32>>,
+	/// tasks waiting in [JoinHandle::join]
+	waiters: VecDeque<TaskId>,
+}
+
+/// the right to wait for a spawned thread. Dropping the handle detaches the
+/// thread, it is reaped all the same when it exits.
+pub struct JoinHandle<T> {
+	tid: TaskId,
+	packet: Arc<L2Sync<Packet<T>>>,
+}
+
+type Main = Box<dyn FnOnce()>;
+
+/// the task entry of spawned threads: takes the closure from the stack and
+/// runs it.
+struct Spawned {}
+impl KThread for Spawned {
+	fn entry() -> ! {
+		let t = Task::current().unwrap();
+		let f = unsafe { Box::from_raw(t.entry_arg() as *mut Main) };
+		f();
+		t.exit(0);
+	}
+}
+
+/// start `f` in a new kernel thread called `name`
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+	F: FnOnce() -> T + Send + 'static,
+	T: Send + 'static,
+{
+	// a good time to clean up after the dead ones
+	Scheduler::reap();
+	let packet = new_packet();
+	let their_packet = packet.clone();
+	let main: Main = Box::new(move || {
+		let r = f();
+		their_packet.lock().result = Some(Ok(r));
+	});
+	// the fat pointer doesn't fit in a word, box it once more
+	let arg = Box::into_raw(Box::new(main)) as u64;
+	start(name, Spawned::get_entry(), arg, packet)
+}
+
+/// start a task at `entry` (see [KThread::get_entry]) with `arg`. The task
+/// never returns a value, it ends in [Task::exit]: joining it only gives the
+/// exit code.
+pub fn spawn_entry(name: &str, entry: u64, arg: u64) -> JoinHandle<()> {
+	Scheduler::reap();
+	start(name, entry, arg, new_packet())
+}
+
+fn new_packet<T>() -> Arc<L2Sync<Packet<T>>> {
+	Arc::new(L2Sync::new(Packet {
+		result: None,
+		waiters: VecDeque::new(),
+	}))
+}
+
+fn start<T: 'static>(
+	name: &str,
+	entry: u64,
+	arg: u64,
+	packet: Arc<L2Sync<Packet<T>>>,
+) -> JoinHandle<T> {
+	// the joiners are woken up on the way out, however the thread ends
+	let exit_packet = packet.clone();
+	let on_exit = Box::new(move |code: i32| {
+		let mut p = exit_packet.lock();
+		if p.result.is_none() {
+			p.result = Some(Err(code));
+		}
+		for w in p.waiters.drain(..) {
+			if let Some(t) = w.get_mut() {
+				unsafe { t.wakeup() };
+			}
+		}
+	});
+	let tid = Task::create_task_arg(name, entry, arg);
+	tid.get_task_ref_mut().on_exit = Some(on_exit);
+	GLOBAL_SCHEDULER.lock().insert_task(tid);
+	JoinHandle { tid, packet }
+}
+
+impl<T> JoinHandle<T> {
+	/// the thread, it's gone after it has been joined or reaped
+	pub fn task(&self) -> TaskId { self.tid }
+
+	pub fn is_finished(&self) -> bool { self.packet.lock().result.is_some() }
+
+	/// wait for the thread to finish and take its result. Err is the exit
+	/// code of a thread that ended without returning: it was killed, or it
+	/// never returns (see [spawn_entry]).
+	pub fn join(self) -> Result<T, i32> {
+		loop {
+			let mut p = self.packet.lock();
+			if let Some(r) = p.result.take() {
+				drop(p);
+				Scheduler::reap();
+				return r;
+			}
+			unsafe {
+				Task::curr_wait_in(&mut p.waiters);
+				// we hold L2, nobody can wake us up before we are gone
+				Scheduler::do_schedule();
+			}
+		}
+	}
+}
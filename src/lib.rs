@@ -31,7 +31,6 @@ pub mod proc;
 extern crate alloc;
 use crate::proc::sched::*;
 use arch::x86_64::interrupt;
-use arch::x86_64::interrupt::pic_8259;
 use arch::x86_64::interrupt::pic_8259::PicDeviceInt;
 use defs::*;
 use kthread::KThread;
@@ -56,12 +55,27 @@ pub unsafe extern "C" fn _entry() -> ! {
 	mm::init();
 	// point of no return: low memory can no longer be accessed after this point
 	mm::drop_init_mapping();
+	// dma pools, the uncached one must not have a cached alias down there
+	mm::dma::init();
 	// initialize proc and sync primitives
 	proc::init();
-	// initialize interrupt timer to roughly ... 50 hz
-	let _interval = interrupt::pit::PIT::set_interval(20000);
-	pic_8259::allow(PicDeviceInt::KEYBOARD);
-	pic_8259::allow(PicDeviceInt::TIMER);
+	// start the clock and the (tickless) timer interrupt
+	machine::acpi::init();
+	machine::time::init();
+	// the timer interrupt comes from the local APIC if there is one, the
+	// device interrupts from the IOAPIC (or the PIC)
+	let has_lapic = interrupt::lapic::init();
+	interrupt::ioapic::init();
+	if cfg!(feature = "rtc_tick") {
+		machine::clockevent::init(&machine::rtc::RTC_CLOCKEVENT);
+		interrupt::allow(PicDeviceInt::RTC);
+	} else if has_lapic {
+		machine::clockevent::init(&interrupt::lapic::LAPIC_CLOCKEVENT);
+	} else {
+		machine::clockevent::init(&interrupt::pit::PIT_CLOCKEVENT);
+		interrupt::allow(PicDeviceInt::TIMER);
+	}
+	interrupt::allow(PicDeviceInt::KEYBOARD);
 	// interrupt should be enabled at the end
 	// run kernel threads
 	create_tasks();
@@ -71,9 +85,9 @@ pub unsafe extern "C" fn _entry() -> ! {
 }
 
 fn create_tasks() {
-	let mut sched = GLOBAL_SCHEDULER.lock();
-	sched.insert_task(Task::create_task(1, kthread::Idle::get_entry()));
-	sched.insert_task(Task::create_task(2, kthread::Meeseeks::get_entry()));
-	sched.insert_task(Task::create_task(3, kthread::Kshell::get_entry()));
-	sched.insert_task(Task::create_task(4, kthread::Lazy::get_entry()));
+	let idle = Task::create_task("idle", kthread::Idle::get_entry());
+	GLOBAL_SCHEDULER.lock().set_idle(idle);
+	kthread::spawn("meeseeks", kthread::Meeseeks::entry);
+	kthread::spawn("kshell", kthread::Kshell::entry);
+	kthread::spawn("lazy", kthread::Lazy::entry);
 }
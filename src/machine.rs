@@ -1,10 +1,14 @@
 //! machine level abstractions for architecture independent devices.
 //! FIXME: still having some x86 coupling.
+pub mod acpi;
 pub mod cgascr;
+pub mod clockevent;
+pub mod clocksource;
 pub mod device_io;
 pub mod interrupt;
 pub mod key;
 pub mod keyctrl;
 pub mod multiboot;
+pub mod rtc;
 pub mod serial;
 pub mod time;
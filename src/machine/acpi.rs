@@ -0,0 +1,117 @@
+//! minimal ACPI support: find the RSDP in the BIOS areas and look up the
+//! system description tables by signature. The tables are read through the
+//! identical mapping and are never modified.
+use crate::defs::P2V;
+use core::mem::size_of;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// physical address of the RSDT or XSDT, 0 if there is no ACPI
+static ROOT_TABLE: AtomicU64 = AtomicU64::new(0);
+/// the root table is the XSDT, with 64 bit entries
+static IS_XSDT: AtomicBool = AtomicBool::new(false);
+
+#[repr(C, packed)]
+struct Rsdp {
+	signature: [u8; 8],
+	checksum: u8,
+	oem_id: [u8; 6],
+	revision: u8,
+	rsdt_address: u32,
+	// ACPI 2.0+
+	length: u32,
+	xsdt_address: u64,
+	ext_checksum: u8,
+	reserved: [u8; 3],
+}
+
+/// the common header of all system description tables, the table body
+/// follows
+#[repr(C, packed)]
+pub struct SdtHeader {
+	pub signature: [u8; 4],
+	pub length: u32,
+	pub revision: u8,
+	pub checksum: u8,
+	pub oem_id: [u8; 6],
+	pub oem_table_id: [u8; 8],
+	pub oem_revision: u32,
+	pub creator_id: u32,
+	pub creator_revision: u32,
+}
+
+impl SdtHeader {
+	/// the table body after the header
+	pub fn body(&self) -> &[u8] {
+		let len = self.length as usize - size_of::<Self>();
+		unsafe {
+			let p = (self as *const Self as *const u8).add(size_of::<Self>());
+			slice::from_raw_parts(p, len)
+		}
+	}
+}
+
+/// bytes sum up to 0
+fn checksum_ok(pa: u64, len: usize) -> bool {
+	let p = P2V(pa).unwrap() as *const u8;
+	let bytes = unsafe { slice::from_raw_parts(p, len) };
+	bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b)) == 0
+}
+
+/// search for the RSDP signature on 16 byte boundaries
+fn scan_rsdp(start: u64, len: u64) -> Option<u64> {
+	let mut pa = start;
+	while pa < start + len {
+		let sig = unsafe { &*(P2V(pa).unwrap() as *const [u8; 8]) };
+		if sig == b"RSD PTR " && checksum_ok(pa, 20) {
+			return Some(pa);
+		}
+		pa += 16;
+	}
+	None
+}
+
+/// find the root table, returns false if there is no ACPI
+pub fn init() -> bool {
+	// the first KiB of the EBDA, then the BIOS ROM area
+	let ebda = unsafe { *(P2V(0x40e).unwrap() as *const u16) } as u64 * 16;
+	let rsdp = scan_rsdp(ebda, 1024).or_else(|| scan_rsdp(0xe0000, 0x20000));
+	let Some(rsdp_pa) = rsdp else {
+		println!("[init] acpi: no rsdp");
+		return false;
+	};
+	let rsdp = unsafe { &*(P2V(rsdp_pa).unwrap() as *const Rsdp) };
+	let xsdt = rsdp.xsdt_address;
+	if rsdp.revision >= 2 && xsdt != 0 && checksum_ok(rsdp_pa, 36) {
+		ROOT_TABLE.store(xsdt, Ordering::Relaxed);
+		IS_XSDT.store(true, Ordering::Relaxed);
+	} else {
+		ROOT_TABLE.store(rsdp.rsdt_address as u64, Ordering::Relaxed);
+	}
+	println!(
+		"[init] acpi: rsdp @ {:#X}, revision {}",
+		rsdp_pa, rsdp.revision
+	);
+	return true;
+}
+
+/// the table with signature `sig`, if present and valid
+pub fn find_table(sig: &[u8; 4]) -> Option<&'static SdtHeader> {
+	let root_pa = ROOT_TABLE.load(Ordering::Relaxed);
+	if root_pa == 0 {
+		return None;
+	}
+	let root = unsafe { &*(P2V(root_pa)? as *const SdtHeader) };
+	let body = root.body();
+	let entry_size = if IS_XSDT.load(Ordering::Relaxed) { 8 } else { 4 };
+	for e in body.chunks_exact(entry_size) {
+		let mut buf = [0u8; 8];
+		buf[..entry_size].copy_from_slice(e);
+		let pa = u64::from_le_bytes(buf);
+		let hdr = unsafe { &*(P2V(pa)? as *const SdtHeader) };
+		if &hdr.signature == sig && checksum_ok(pa, hdr.length as usize) {
+			return Some(hdr);
+		}
+	}
+	None
+}
@@ -0,0 +1,113 @@
+//! clock events: the timer interrupt is programmed one-shot for the next event
+//! instead of firing periodically (tickless). The next event is the earliest
+//! of
+//! - the next scheduler tick, every [TICK_NS] while the cpu is busy. The tick
+//!   is stopped while the idle task runs.
+//! - the earliest pending kernel timer (see [crate::proc::timer]).
+//!
+//! If there is neither, no timer interrupt comes at all. A device that can't
+//! wait long enough is programmed for as long as it can and reprogrammed when
+//! it fires.
+use crate::machine::interrupt::{irq_restore, irq_save};
+use crate::machine::time;
+use crate::proc::sync::L3Sync;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// a timer that can raise one interrupt after a given delay
+pub trait ClockEventDevice: Sync {
+	fn name(&self) -> &'static str;
+	/// raise the timer interrupt once in about `ns`, returns the actual delay.
+	/// Replaces what was programmed before.
+	fn program(&self, ns: u64) -> u64;
+	/// cancel the programmed interrupt
+	fn stop(&self);
+	/// the longest delay the device can be programmed for
+	fn max_delta(&self) -> u64;
+}
+
+/// scheduler tick period
+pub const TICK_NS: u64 = 20_000_000;
+const NONE: u64 = u64::MAX;
+
+static DEVICE: L3Sync<Option<&'static dyn ClockEventDevice>> =
+	L3Sync::new(None);
+/// when the next scheduler tick is due, [NONE] if the tick is stopped
+static NEXT_TICK: AtomicU64 = AtomicU64::new(NONE);
+/// expiry of the earliest kernel timer
+static NEXT_TIMER: AtomicU64 = AtomicU64::new(NONE);
+/// when the device fires, [NONE] if it's not armed
+static ARMED: AtomicU64 = AtomicU64::new(NONE);
+
+/// start programming `dev` (with the tick running), call after [time::init]
+pub fn init(dev: &'static dyn ClockEventDevice) {
+	let r = irq_save();
+	if let Some(old) = DEVICE.l3_get_ref_mut().replace(dev) {
+		old.stop();
+	}
+	ARMED.store(NONE, Ordering::Relaxed);
+	irq_restore(r);
+	println!(
+		"[init] clockevent: {}, max {} us",
+		dev.name(),
+		dev.max_delta() / 1000
+	);
+	start_tick();
+}
+
+/// resume the scheduler tick, if stopped
+pub fn start_tick() {
+	if NEXT_TICK.load(Ordering::Relaxed) == NONE {
+		NEXT_TICK.store(time::nsec() + TICK_NS, Ordering::Relaxed);
+		reprogram();
+	}
+}
+
+pub fn stop_tick() {
+	NEXT_TICK.store(NONE, Ordering::Relaxed);
+	reprogram();
+}
+
+/// the kernel timers tell us their earliest expiry
+pub fn set_next_timer(expires: Option<u64>) {
+	NEXT_TIMER.store(expires.unwrap_or(NONE), Ordering::Relaxed);
+	reprogram();
+}
+
+/// call this in the timer prologue, the device has fired. Returns whether a
+/// scheduler tick is due. The epilogue must [reprogram] the device.
+pub fn tick_due() -> bool {
+	ARMED.store(NONE, Ordering::Relaxed);
+	let next = NEXT_TICK.load(Ordering::Relaxed);
+	let now = time::nsec();
+	if next == NONE || now < next {
+		return false;
+	}
+	// missed ticks are not made up for
+	let missed = (now - next) / TICK_NS;
+	NEXT_TICK.store(next + (missed + 1) * TICK_NS, Ordering::Relaxed);
+	return true;
+}
+
+/// program the device for the next event, unless it fires early enough
+/// already
+pub fn reprogram() {
+	let r = irq_save();
+	let next = u64::min(
+		NEXT_TICK.load(Ordering::Relaxed),
+		NEXT_TIMER.load(Ordering::Relaxed),
+	);
+	let armed = ARMED.load(Ordering::Relaxed);
+	if let Some(dev) = DEVICE.l3_get_ref() {
+		if next == NONE {
+			if armed != NONE {
+				dev.stop();
+				ARMED.store(NONE, Ordering::Relaxed);
+			}
+		} else if next < armed {
+			let now = time::nsec();
+			let d = dev.program(next.saturating_sub(now));
+			ARMED.store(now + d, Ordering::Relaxed);
+		}
+	}
+	irq_restore(r);
+}
@@ -0,0 +1,129 @@
+//! clock sources: free-running counters that [crate::machine::time] reads
+//! the monotonic time from.
+//! - the TSC, calibrated against the PIT. It's the cheapest to read, but only
+//!   a good clock if it's invariant (runs at a constant rate in all power
+//!   states), which cpuid tells.
+//! - the HPET main counter, found through ACPI. The fallback if the TSC is not
+//!   invariant; only 64 bit counters are used, a 32 bit one wraps within
+//!   minutes and we may go that long without looking at it.
+//!
+//! Cycles are converted to ns with a fixed point multiplier.
+use crate::arch::x86_64::cpuid;
+use crate::arch::x86_64::interrupt::pit::PIT;
+use crate::arch::x86_64::misc::rdtsc;
+use crate::machine::acpi;
+use crate::machine::device_io::{Mmio, MmioRegion};
+use core::mem;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+	/// not initialized, the time is 0
+	None = 0,
+	Tsc,
+	Hpet,
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::None as u8);
+/// counter value at time 0
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// ns per cycle, fixed point with [MULT_SHIFT] fraction bits
+static MULT: AtomicU64 = AtomicU64::new(0);
+const MULT_SHIFT: u32 = 32;
+/// virtual address of the HPET main counter
+static HPET_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// HPET registers
+const HPET_CAP: u64 = 0x0;
+const HPET_CONF: u64 = 0x10;
+const HPET_COUNTER_REG: u64 = 0xf0;
+const HPET_CAP_64BIT: u64 = 1 << 13;
+const HPET_CONF_ENABLE: u64 = 1 << 0;
+/// the counter period (in fs) must not be larger than that
+const HPET_MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// pick and set up the clock source, time starts now. Call with interrupts
+/// disabled, after mm init.
+pub fn init() {
+	let invariant = cpuid::has_invariant_tsc();
+	let hpet_period = if invariant { None } else { hpet_init() };
+	let (src, mult) = match hpet_period {
+		Some(fs) => (ClockSource::Hpet, (fs << MULT_SHIFT) / 1_000_000),
+		None => {
+			let khz = calibrate_tsc();
+			println!(
+				"[init] clocksource: tsc {}.{:03} MHz{}",
+				khz / 1000,
+				khz % 1000,
+				if invariant { "" } else { " (not invariant)" }
+			);
+			(ClockSource::Tsc, (1_000_000 << MULT_SHIFT) / khz)
+		}
+	};
+	MULT.store(mult, Ordering::Relaxed);
+	SOURCE.store(src as u8, Ordering::Relaxed);
+	BASE.store(read_cycles(src), Ordering::Relaxed);
+}
+
+pub fn current() -> ClockSource {
+	match SOURCE.load(Ordering::Relaxed) {
+		1 => ClockSource::Tsc,
+		2 => ClockSource::Hpet,
+		_ => ClockSource::None,
+	}
+}
+
+/// ns since [init]
+#[inline]
+pub fn nsec() -> u64 {
+	let src = current();
+	let cycles = read_cycles(src).wrapping_sub(BASE.load(Ordering::Relaxed));
+	let mult = MULT.load(Ordering::Relaxed);
+	((cycles as u128 * mult as u128) >> MULT_SHIFT) as u64
+}
+
+#[inline]
+fn read_cycles(src: ClockSource) -> u64 {
+	match src {
+		ClockSource::None => 0,
+		ClockSource::Tsc => rdtsc(),
+		ClockSource::Hpet => {
+			let va = HPET_COUNTER.load(Ordering::Relaxed);
+			unsafe { Mmio::<u64>::at(va) }.read()
+		}
+	}
+}
+
+/// TSC frequency in kHz: the best of a few PIT measurements, an SMI or a slow
+/// emulated port access only makes a measurement longer.
+fn calibrate_tsc() -> u64 {
+	(0..3).map(|_| PIT::calibrate_tsc()).min().unwrap()
+}
+
+/// map and start the HPET main counter. Returns its period in fs, None if
+/// there is no (usable) HPET.
+fn hpet_init() -> Option<u64> {
+	let hdr = acpi::find_table(b"HPET")?;
+	// the base address is in the generic address structure at offset 4 of
+	// the body, its address field is at offset 4 of that
+	let body = hdr.body();
+	let pa = u64::from_le_bytes(body.get(8..16)?.try_into().ok()?);
+	let regs = MmioRegion::map(pa, 0x400).ok()?;
+	let cap = regs.reg::<u64>(HPET_CAP).read();
+	let period = cap >> 32;
+	if cap & HPET_CAP_64BIT == 0 || period == 0 || period > HPET_MAX_PERIOD_FS {
+		println!("[init] clocksource: hpet @ {:#X} not usable", pa);
+		return None;
+	}
+	regs.reg::<u64>(HPET_CONF).set_bits(HPET_CONF_ENABLE);
+	HPET_COUNTER.store(regs.va() + HPET_COUNTER_REG, Ordering::Relaxed);
+	// the counter stays mapped for good
+	mem::forget(regs);
+	println!(
+		"[init] clocksource: hpet @ {:#X}, {} kHz",
+		pa,
+		1_000_000_000_000 / period
+	);
+	Some(period)
+}
@@ -1,5 +1,10 @@
 #[cfg(target_arch = "x86_64")]
 pub use crate::arch::x86_64::io_port::*;
+pub use crate::arch::x86_64::paging::pat::CacheMode;
+use crate::mm::ioremap::{ioremap, iounmap};
+use core::cell::UnsafeCell;
+use core::mem::{align_of, size_of};
+use core::ptr;
 
 // either use the io functions directly, or via a IOPort instance.
 pub struct IOPort(u16);
@@ -10,3 +15,77 @@ impl IOPort {
 	pub fn outw(&self, val: u16) { outw(self.0, val); }
 	pub fn outb(&self, val: u8) { outb(self.0, val); }
 }
+
+/// a memory mapped device register, accessed with volatile reads and writes
+/// only. Get one with [Mmio::at] or [MmioRegion::reg], or make it a field of a
+/// `#[repr(C)]` register block. The mapping must be uncached, see
+/// [crate::mm::ioremap].
+#[repr(transparent)]
+pub struct Mmio<T: Copy>(UnsafeCell<T>);
+
+// registers are shared by nature, the driver decides about locking
+unsafe impl<T: Copy> Sync for Mmio<T> {}
+
+impl<T: Copy> Mmio<T> {
+	/// unsafe: `va` must map a register of type T for the lifetime 'a
+	pub unsafe fn at<'a>(va: u64) -> &'a Self { &*(va as *const Self) }
+	#[inline]
+	pub fn read(&self) -> T { unsafe { ptr::read_volatile(self.0.get()) } }
+	#[inline]
+	pub fn write(&self, val: T) {
+		unsafe { ptr::write_volatile(self.0.get(), val) }
+	}
+	/// read, modify and write back. This is not atomic.
+	#[inline]
+	pub fn modify(&self, f: impl FnOnce(T) -> T) { self.write(f(self.read())) }
+}
+
+macro_rules! mmio_bits {
+	($($t:ty),*) => {$(
+		impl Mmio<$t> {
+			#[inline]
+			pub fn set_bits(&self, bits: $t) { self.modify(|v| v | bits) }
+			#[inline]
+			pub fn clear_bits(&self, bits: $t) { self.modify(|v| v & !bits) }
+		}
+	)*};
+}
+mmio_bits!(u8, u16, u32, u64);
+
+/// an ioremapped range of registers, unmapped on drop
+pub struct MmioRegion {
+	va: u64,
+	size: u64,
+}
+
+impl MmioRegion {
+	/// map `size` bytes of registers at physical address `pa` uncached
+	pub fn map(pa: u64, size: u64) -> Result<Self, &'static str> {
+		Self::map_with(pa, size, CacheMode::UC)
+	}
+
+	pub fn map_with(
+		pa: u64,
+		size: u64,
+		mode: CacheMode,
+	) -> Result<Self, &'static str> {
+		let va = ioremap(pa, size, mode)?;
+		Ok(Self { va, size })
+	}
+
+	#[inline]
+	pub fn va(&self) -> u64 { self.va }
+
+	/// the register at byte offset `off`. Panics if it's out of range or
+	/// misaligned.
+	pub fn reg<T: Copy>(&self, off: u64) -> &Mmio<T> {
+		let sz = size_of::<T>() as u64;
+		assert!(off + sz <= self.size, "mmio offset {:#X} out of range", off);
+		assert!((self.va + off) & (align_of::<T>() as u64 - 1) == 0);
+		unsafe { Mmio::at(self.va + off) }
+	}
+}
+
+impl Drop for MmioRegion {
+	fn drop(&mut self) { iounmap(self.va) }
+}
@@ -23,7 +23,7 @@ use core::sync::atomic::AtomicU32;
 use core::sync::atomic::Ordering;
 
 #[cfg(target_arch = "x86_64")]
-use crate::arch::x86_64::interrupt::{pic_8259, pic_8259::PicDeviceInt as PD};
+use crate::arch::x86_64::interrupt::{self, pic_8259::PicDeviceInt as PD};
 
 use super::key::Modifiers;
 
@@ -333,13 +333,13 @@ impl KeyboardController {
 #[cfg(target_arch = "x86_64")]
 impl KeyboardController {
 	#[inline(always)]
-	fn disable_keyboard_int() { pic_8259::forbid(PD::KEYBOARD); }
+	fn disable_keyboard_int() { interrupt::forbid(PD::KEYBOARD); }
 
 	#[inline(always)]
-	fn enable_keyboard_int() { pic_8259::allow(PD::KEYBOARD); }
+	fn enable_keyboard_int() { interrupt::allow(PD::KEYBOARD); }
 
 	#[inline(always)]
-	fn is_int_masked() -> bool { pic_8259::is_masked(PD::KEYBOARD) }
+	fn is_int_masked() -> bool { interrupt::is_masked(PD::KEYBOARD) }
 }
 
 // for whatever reason the PS/2 keyboard controls the "shutdown"...
@@ -0,0 +1,252 @@
+//! the CMOS real time clock: the date and time at boot, and its periodic
+//! interrupt (IRQ8), which can serve as the timer interrupt instead of the APIC
+//! timer or the PIT (`--features rtc_tick`).
+//!
+//! The clock is assumed to run in UTC.
+use crate::machine::acpi;
+use crate::machine::clockevent::{self, ClockEventDevice};
+use crate::machine::device_io::IOPort;
+use crate::machine::interrupt::{irq_restore, irq_save};
+use crate::proc::sched::Scheduler;
+use crate::proc::sync::IRQHandlerEpilogue;
+use crate::proc::timer;
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub struct RTC {}
+
+/// broken down UTC time
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DateTime {
+	pub year: u16,
+	pub month: u8,
+	pub day: u8,
+	pub hour: u8,
+	pub minute: u8,
+	pub second: u8,
+}
+
+/// the periodic rate programmed in register A, 0 if the interrupt is off
+static RATE: AtomicU8 = AtomicU8::new(0);
+
+impl RTC {
+	const INDEX_PORT: IOPort = IOPort::new(0x70);
+	const DATA_PORT: IOPort = IOPort::new(0x71);
+	const SECONDS: u8 = 0x00;
+	const MINUTES: u8 = 0x02;
+	const HOURS: u8 = 0x04;
+	const DAY: u8 = 0x07;
+	const MONTH: u8 = 0x08;
+	const YEAR: u8 = 0x09;
+	const STATUS_A: u8 = 0x0a;
+	const STATUS_B: u8 = 0x0b;
+	const STATUS_C: u8 = 0x0c;
+	/// A: an update is in progress, the time registers are not valid
+	const A_UIP: u8 = 1 << 7;
+	/// B: periodic interrupt enable
+	const B_PIE: u8 = 1 << 6;
+	/// B: 24 hour mode
+	const B_24H: u8 = 1 << 1;
+	/// B: binary, not BCD values
+	const B_BINARY: u8 = 1 << 2;
+	/// 12 hour mode: the hour has this bit set after noon
+	const HOUR_PM: u8 = 1 << 7;
+	/// the fastest and the slowest periodic rate: 32768 >> (rate - 1) Hz
+	const MIN_RATE: u8 = 3;
+	const MAX_RATE: u8 = 15;
+
+	/// read a register, call with interrupts disabled
+	fn read_reg(reg: u8) -> u8 {
+		Self::INDEX_PORT.outb(reg);
+		Self::DATA_PORT.inb()
+	}
+
+	fn write_reg(reg: u8, val: u8) {
+		Self::INDEX_PORT.outb(reg);
+		Self::DATA_PORT.outb(val);
+	}
+
+	/// the raw time registers, read outside of an update
+	fn read_raw(century: Option<u8>) -> [u8; 7] {
+		while Self::read_reg(Self::STATUS_A) & Self::A_UIP != 0 {}
+		[
+			Self::read_reg(Self::SECONDS),
+			Self::read_reg(Self::MINUTES),
+			Self::read_reg(Self::HOURS),
+			Self::read_reg(Self::DAY),
+			Self::read_reg(Self::MONTH),
+			Self::read_reg(Self::YEAR),
+			century.map_or(0, Self::read_reg),
+		]
+	}
+
+	/// the current date and time
+	pub fn read() -> DateTime {
+		let century = acpi_century();
+		let r = irq_save();
+		// an update may start right after we checked, read until we get the
+		// same values twice
+		let mut raw = Self::read_raw(century);
+		loop {
+			let again = Self::read_raw(century);
+			if again == raw {
+				break;
+			}
+			raw = again;
+		}
+		let b = Self::read_reg(Self::STATUS_B);
+		irq_restore(r);
+
+		let [mut sec, mut min, hour, mut day, mut mon, mut year, mut cent] =
+			raw;
+		let pm = hour & Self::HOUR_PM != 0;
+		let mut hour = hour & !Self::HOUR_PM;
+		if b & Self::B_BINARY == 0 {
+			for v in [
+				&mut sec, &mut min, &mut hour, &mut day, &mut mon, &mut year,
+				&mut cent,
+			] {
+				*v = bcd_to_bin(*v);
+			}
+		}
+		if b & Self::B_24H == 0 {
+			// 12 am is 0 o'clock
+			hour = hour % 12 + if pm { 12 } else { 0 };
+		}
+		// no century register: assume 20xx
+		let cent = if cent == 0 { 20 } else { cent };
+		DateTime {
+			year: cent as u16 * 100 + year as u16,
+			month: mon,
+			day,
+			hour,
+			minute: min,
+			second: sec,
+		}
+	}
+
+	/// set the periodic interrupt rate (see [RTC::MIN_RATE]) and enable it
+	fn set_periodic(rate: u8) {
+		let r = irq_save();
+		if RATE.load(Ordering::Relaxed) != rate {
+			let a = Self::read_reg(Self::STATUS_A);
+			Self::write_reg(Self::STATUS_A, (a & 0xf0) | rate);
+			let b = Self::read_reg(Self::STATUS_B);
+			Self::write_reg(Self::STATUS_B, b | Self::B_PIE);
+			RATE.store(rate, Ordering::Relaxed);
+		}
+		irq_restore(r);
+	}
+
+	fn rate_ns(rate: u8) -> u64 { 1_000_000_000 / (32768 >> (rate - 1)) }
+}
+
+/// the CMOS index of the century register, if the FADT tells one
+fn acpi_century() -> Option<u8> {
+	// the century field is at offset 108 of the FADT
+	let fadt = acpi::find_table(b"FACP")?;
+	let c = *fadt.body().get(108 - 36)?;
+	if c == 0 {
+		return None;
+	}
+	Some(c)
+}
+
+fn bcd_to_bin(v: u8) -> u8 { (v & 0x0f) + (v >> 4) * 10 }
+
+impl DateTime {
+	/// seconds since 1970-01-01 00:00:00
+	pub fn to_unix(&self) -> u64 {
+		// days_from_civil() from Howard Hinnant's date algorithms
+		let (m, d) = (self.month as u64, self.day as u64);
+		let y = self.year as u64 - (m <= 2) as u64;
+		let era = y / 400;
+		let yoe = y - era * 400;
+		let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+		let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+		let days = era * 146097 + doe - 719468;
+		days * 86400
+			+ self.hour as u64 * 3600
+			+ self.minute as u64 * 60
+			+ self.second as u64
+	}
+
+	pub fn from_unix(secs: u64) -> Self {
+		// civil_from_days()
+		let z = secs / 86400 + 719468;
+		let era = z / 146097;
+		let doe = z - era * 146097;
+		let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+		let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+		let mp = (5 * doy + 2) / 153;
+		let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+		let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+		let year = (yoe + era * 400 + (month <= 2) as u64) as u16;
+		let s = secs % 86400;
+		Self {
+			year,
+			month,
+			day,
+			hour: (s / 3600) as u8,
+			minute: (s / 60 % 60) as u8,
+			second: (s % 60) as u8,
+		}
+	}
+}
+
+impl fmt::Display for DateTime {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+			self.year,
+			self.month,
+			self.day,
+			self.hour,
+			self.minute,
+			self.second
+		)
+	}
+}
+
+/// the periodic interrupt as a clock event device: it keeps firing at the
+/// programmed rate, the clock event layer ignores early interrupts.
+pub static RTC_CLOCKEVENT: RTC = RTC {};
+
+impl ClockEventDevice for RTC {
+	fn name(&self) -> &'static str { "rtc" }
+
+	fn program(&self, ns: u64) -> u64 {
+		// the slowest rate that fires within `ns`
+		let rate = (Self::MIN_RATE..=Self::MAX_RATE)
+			.rev()
+			.find(|r| Self::rate_ns(*r) <= ns)
+			.unwrap_or(Self::MIN_RATE);
+		Self::set_periodic(rate);
+		Self::rate_ns(rate)
+	}
+
+	fn stop(&self) {
+		let r = irq_save();
+		let b = Self::read_reg(Self::STATUS_B);
+		Self::write_reg(Self::STATUS_B, b & !Self::B_PIE);
+		RATE.store(0, Ordering::Relaxed);
+		irq_restore(r);
+	}
+
+	fn max_delta(&self) -> u64 { Self::rate_ns(Self::MAX_RATE) }
+}
+
+impl IRQHandlerEpilogue for RTC {
+	unsafe fn do_prologue() {
+		// the RTC raises no more interrupts until C is read
+		Self::read_reg(Self::STATUS_C);
+		if clockevent::tick_due() {
+			Scheduler::tick();
+		}
+	}
+	unsafe fn do_epilogue() {
+		timer::run_expired();
+		clockevent::reprogram();
+	}
+}
@@ -1,23 +1,34 @@
-//! system level timer
+//! system level timer: monotonic time since boot. It's read from a free-running
+//! counter (see [crate::machine::clocksource]) and doesn't depend on timer
+//! interrupts, which may not come for a while (see
+//! [crate::machine::clockevent]).
+//!
+//! The realtime (wall clock) is the date and time read from the RTC at boot
+//! plus the monotonic time.
 
+use crate::machine::clocksource;
+use crate::machine::rtc::RTC;
 use core::sync::atomic::{AtomicU64, Ordering};
-// using PIT: this is not really accurate
-// 64 bit nanosecond timer takes hundreds of years to wrap around.
-pub static HW_NS: AtomicU64 = AtomicU64::new(0);
-// TODO remove hardcode
-pub static HW_INCREMENT: u64 = 19999708;
 
-// call this in the timer interrupt prologue
-pub fn tick() -> u64 { HW_NS.fetch_add(HW_INCREMENT, Ordering::Relaxed) }
+/// realtime in ns since the epoch at monotonic time 0
+static REALTIME_BASE: AtomicU64 = AtomicU64::new(0);
 
-pub fn sec() -> u64 {
-	let ns = HW_NS.load(Ordering::Relaxed);
-	ns / 1_000_000_000
+/// pick a clock source, time starts now. Call this with interrupts disabled,
+/// after [crate::machine::acpi::init].
+pub fn init() {
+	clocksource::init();
+	let boot = RTC::read();
+	let base = (boot.to_unix() * 1_000_000_000).saturating_sub(nsec());
+	REALTIME_BASE.store(base, Ordering::Relaxed);
+	println!("[init] time: {}", boot);
 }
 
-pub fn msec() -> u64 {
-	let ns = HW_NS.load(Ordering::Relaxed);
-	ns / 1_000_000
-}
+pub fn sec() -> u64 { nsec() / 1_000_000_000 }
+
+pub fn msec() -> u64 { nsec() / 1_000_000 }
+
+/// 0 before [init]
+pub fn nsec() -> u64 { clocksource::nsec() }
 
-pub fn nsec() -> u64 { HW_NS.load(Ordering::Relaxed) }
+/// ns since 1970-01-01 00:00:00 UTC
+pub fn realtime_nsec() -> u64 { REALTIME_BASE.load(Ordering::Relaxed) + nsec() }
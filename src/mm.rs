@@ -1,8 +1,20 @@
 //! memory management unit
 
+#[cfg(feature = "debug_heap")]
+pub mod debug_heap;
+pub mod dma;
+pub mod frame;
+pub mod ioremap;
 mod pma;
+pub mod reclaim;
+pub mod shm;
 pub mod vmm;
+pub mod zram;
 
+use crate::arch::x86_64::paging;
+#[cfg(feature = "kpti")]
+use crate::arch::x86_64::paging::kpti;
+use crate::arch::x86_64::paging::pcid::{self, InvpcidType};
 use crate::arch::x86_64::paging::{get_root, Pagetable};
 use crate::defs::*;
 use crate::machine::multiboot;
@@ -10,12 +22,21 @@ use alloc::alloc::{alloc, alloc_zeroed, dealloc, Layout};
 use alloc::vec::Vec;
 use core::arch::asm;
 use core::ops::Range;
+use frame::{FrameFlags, FrameOwner, FRAME_TABLE};
 use lazy_static::lazy_static;
+#[cfg(not(feature = "debug_heap"))]
 use linked_list_allocator::LockedHeap;
+use reclaim::ReclaimHeap;
 use spin::Mutex;
 
+#[cfg(not(feature = "debug_heap"))]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: ReclaimHeap<LockedHeap> = ReclaimHeap(LockedHeap::empty());
+
+#[cfg(feature = "debug_heap")]
+#[global_allocator]
+static ALLOCATOR: ReclaimHeap<debug_heap::DebugHeap> =
+	ReclaimHeap(debug_heap::DebugHeap::empty());
 
 lazy_static! {
 	pub static ref KSTACK_ALLOCATOR: Mutex<KStackAllocator> =
@@ -78,6 +99,15 @@ pub fn init() {
 		P2V(pr.start).unwrap(),
 		P2V(pr.end).unwrap()
 	);
+	{
+		let _nr = reclaim::no_reclaim();
+		FRAME_TABLE.lock().init(pr);
+	}
+	paging::init();
+	println!(
+		"[init] mm: {} frames tracked",
+		FRAME_TABLE.lock().nr_frames()
+	);
 }
 
 /// wrapper around the global allocator with caching
@@ -90,6 +120,7 @@ pub struct KStackAllocator {
 /// reserve some memory during system init to guarantee that we can at least
 impl KStackAllocator {
 	const KSTACK_ALLOC_POOL_CAP: usize = 16;
+	const KSTACK_PAGES: u64 = Mem::KERNEL_STACK_SIZE / Mem::PAGE_SIZE;
 	const KSTACK_LAYOUT: Layout = unsafe {
 		Layout::from_size_align_unchecked(
 			Mem::KERNEL_STACK_SIZE as usize,
@@ -107,7 +138,7 @@ impl KStackAllocator {
 		if let Some(addr) = self.pool.pop() {
 			return addr;
 		} else {
-			return alloc(Self::KSTACK_LAYOUT) as u64;
+			return Self::alloc_stack();
 		}
 	}
 
@@ -117,6 +148,9 @@ impl KStackAllocator {
 		if self.pool.len() < Self::KSTACK_ALLOC_POOL_CAP {
 			self.pool.push(addr);
 		} else {
+			FRAME_TABLE
+				.lock()
+				.mark_free(V2P(addr).unwrap(), Self::KSTACK_PAGES);
 			dealloc(addr as *mut u8, Self::KSTACK_LAYOUT);
 		}
 	}
@@ -125,27 +159,158 @@ impl KStackAllocator {
 	/// (although unlikely)
 	pub unsafe fn populate(&mut self) {
 		for _ in 0..Self::KSTACK_ALLOC_POOL_CAP {
-			self.pool.push(alloc(Self::KSTACK_LAYOUT) as u64);
+			self.pool.push(Self::alloc_stack());
 		}
 	}
+
+	/// get a fresh stack from the heap allocator, pooled stacks keep their
+	/// STACK owner in the frame table.
+	unsafe fn alloc_stack() -> u64 {
+		let addr = alloc(Self::KSTACK_LAYOUT) as u64;
+		if let Some(pa) = V2P(addr) {
+			FRAME_TABLE.lock().mark_alloc(
+				pa,
+				Self::KSTACK_PAGES,
+				FrameOwner::STACK,
+				FrameFlags::NONE,
+			);
+		}
+		addr
+	}
 }
 
 const LAYOUT_4K_ALIGNED: Layout =
 	unsafe { Layout::from_size_align_unchecked(0x1000, 0x1000) };
-/// allocate 4k aligned memory.
+/// allocate 4k aligned memory and record the frame for `owner`. User pages are
+/// reclaimed when the heap is exhausted (see [ReclaimHeap]). Returns 0 on OOM.
 /// TODO create a buffer (like in KStackAllocator) for performance.
-pub fn allocate_4k() -> u64 {
-	return unsafe { alloc(LAYOUT_4K_ALIGNED) } as u64;
+pub fn allocate_4k(owner: FrameOwner) -> u64 {
+	let va = unsafe { alloc(LAYOUT_4K_ALIGNED) } as u64;
+	record_4k(va, owner, FrameFlags::NONE);
+	return va;
+}
+
+pub fn allocate_4k_zeroed(owner: FrameOwner) -> u64 {
+	let va = unsafe { alloc_zeroed(LAYOUT_4K_ALIGNED) } as u64;
+	record_4k(va, owner, FrameFlags::ZEROED);
+	return va;
+}
+
+fn record_4k(va: u64, owner: FrameOwner, flags: FrameFlags) {
+	if let Some(pa) = V2P(va) {
+		FRAME_TABLE.lock().mark_alloc(pa, 1, owner, flags);
+	}
+}
+
+/// drop a reference to a frame given by [allocate_4k], the frame is given back
+/// to the heap when the last reference is gone. Returns the remaining refcount.
+/// unsafe: `va` must be a (id-mapped) address returned by [allocate_4k]
+pub unsafe fn free_4k(va: u64) -> u32 {
+	let pa = V2P(va).unwrap();
+	let rc = FRAME_TABLE.lock().ref_dec(pa);
+	if rc == 0 {
+		dealloc(va as *mut u8, LAYOUT_4K_ALIGNED);
+	}
+	return rc;
+}
+
+/// a snapshot of the physical memory usage
+pub struct MemStats {
+	/// frames covered by the frame table
+	pub total_frames: u64,
+	/// whole frames that are still available in the heap
+	pub free_frames: u64,
+	pub heap_size: u64,
+	pub heap_used: u64,
+	/// number of frames per [FrameOwner]
+	pub owned: [u64; FrameOwner::COUNT],
+	/// pages on the reclaim LRU list (including stale entries)
+	pub lru_pages: u64,
+	/// swapped out pages and their compressed size
+	pub swap_pages: u64,
+	pub swap_bytes: u64,
 }
-pub fn allocate_4k_zeroed() -> u64 {
-	return unsafe { alloc_zeroed(LAYOUT_4K_ALIGNED) } as u64;
+
+pub fn stats() -> MemStats {
+	let (heap_size, heap_used, heap_free) = {
+		let h = ALLOCATOR.lock();
+		(h.size() as u64, h.used() as u64, h.free() as u64)
+	};
+	let lru_pages = reclaim::LRU.lock().len() as u64;
+	let (swap_pages, swap_bytes) = {
+		let z = zram::ZRAM.lock();
+		(z.nr_pages, z.nr_bytes)
+	};
+	let ft = FRAME_TABLE.lock();
+	let mut owned = [0; FrameOwner::COUNT];
+	for o in FrameOwner::ALL {
+		owned[o as usize] = ft.nr_owned(o);
+	}
+	MemStats {
+		total_frames: ft.nr_frames(),
+		free_frames: heap_free / Mem::PAGE_SIZE,
+		heap_size,
+		heap_used,
+		owned,
+		lru_pages,
+		swap_pages,
+		swap_bytes,
+	}
 }
 
-/// invalidate a single page mapping in tlb
-pub fn invlpg(va: u64) { unsafe { asm!("invlpg [{0}]", in(reg) va) }; }
+/// check the heap for corruption (feature `debug_heap`). Returns the number of
+/// corrupted blocks, details are reported on the serial console.
+#[cfg(feature = "debug_heap")]
+pub fn heap_check() -> Option<usize> {
+	let r = ALLOCATOR.check();
+	println!(
+		"heap check: {} live, {} quarantined, {} bad",
+		r.nr_live, r.nr_quarantined, r.nr_bad
+	);
+	Some(r.nr_bad)
+}
 
-/// flush the whole tlb
+#[cfg(not(feature = "debug_heap"))]
+pub fn heap_check() -> Option<usize> { None }
+
+/// invalidate a single page mapping in the tlb of the current address space.
+/// Kernel addresses are shared by all address spaces, they are invalidated in
+/// all of them.
+pub fn invlpg(va: u64) {
+	unsafe { asm!("invlpg [{0}]", in(reg) va) };
+	if va >= Mem::USER_END && pcid::enabled() {
+		if pcid::has_invpcid() {
+			pcid::invpcid(InvpcidType::ALL, 0, 0);
+		} else {
+			pcid::mark_all_stale();
+		}
+	}
+	#[cfg(feature = "kpti")]
+	kpti::flush_user();
+}
+
+/// invalidate a single page mapping in the address space `pt_root`, which may
+/// or may not be the current one. Kernel addresses are shared, they are always
+/// invalidated locally (and in all address spaces).
+pub fn invlpg_root(pt_root: u64, va: u64) {
+	if va >= Mem::USER_END || pt_root == get_root() {
+		invlpg(va);
+	} else {
+		pcid::invalidate(pt_root, va);
+	}
+}
+
+/// flush the whole tlb, in all address spaces
 pub fn flush_tlb() {
+	#[cfg(feature = "kpti")]
+	kpti::flush_user();
+	if pcid::has_invpcid() {
+		pcid::invpcid(InvpcidType::ALL_GLOBAL, 0, 0);
+		return;
+	}
+	if pcid::enabled() {
+		pcid::mark_all_stale();
+	}
 	unsafe {
 		asm!(
 			"
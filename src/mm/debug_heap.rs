@@ -0,0 +1,352 @@
+//! debug wrapper around the heap allocator (feature `debug_heap`). Every
+//! allocation gets a header and redzones:
+//!
+//! ```text
+//! base           hdr            user                  user+size
+//! | (align pad)  | BlockHdr     | redzone | user data | redzone ... |
+//! ```
+//!
+//! Fresh memory is filled with [POISON_INUSE], freed memory with
+//! [POISON_FREE] and then parked in a quarantine for a while before it's given
+//! back to the heap, so that use-after-free writes are caught when the block
+//! leaves the quarantine. Blocks are checked on free and on demand (see
+//! [DebugHeap::check]); a corrupted block is reported with the return addresses
+//! recorded at allocation time (resolve them with addr2line).
+//!
+//! Page aligned allocations (frames, kernel stacks) are passed through
+//! unchanged: they are handed out as whole pages and tracked by the frame
+//! table, padding them would double their size.
+use crate::defs::*;
+use core::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use core::mem::size_of;
+use core::ops::Deref;
+use core::ptr;
+use linked_list_allocator::LockedHeap;
+use spin::Mutex;
+
+pub const REDZONE: usize = 16;
+pub const REDZONE_BYTE: u8 = 0xbb;
+/// fill pattern for freshly allocated memory
+pub const POISON_INUSE: u8 = 0x5a;
+/// fill pattern for freed memory
+pub const POISON_FREE: u8 = 0x6b;
+const QUARANTINE_CAP: usize = 256;
+const QUARANTINE_BYTES: usize = 1 << 20;
+/// number of return addresses recorded per allocation
+const NR_SITES: usize = 4;
+const MAGIC_LIVE: u64 = 0x4c49_5645_424c_4b21;
+const MAGIC_FREE: u64 = 0x4652_4545_424c_4b21;
+
+#[repr(C)]
+struct BlockHdr {
+	magic: u64,
+	size: usize,
+	align: usize,
+	/// offset of the user pointer from the underlying heap block
+	offset: usize,
+	/// live list (allocated blocks only)
+	prev: u64,
+	next: u64,
+	site: [u64; NR_SITES],
+}
+
+impl BlockHdr {
+	#[inline]
+	fn user(&self) -> u64 {
+		self as *const Self as u64 + (size_of::<Self>() + REDZONE) as u64
+	}
+	#[inline]
+	fn base(&self) -> u64 { self.user() - self.offset as u64 }
+	#[inline]
+	fn inner_layout(&self) -> Layout {
+		inner_layout(self.size, self.align).unwrap()
+	}
+	unsafe fn from_user<'a>(user: u64) -> &'a mut Self {
+		&mut *((user - (size_of::<Self>() + REDZONE) as u64) as *mut Self)
+	}
+}
+
+/// offset of the user pointer in the underlying block
+#[inline]
+fn user_offset(align: usize) -> usize {
+	(size_of::<BlockHdr>() + REDZONE).next_multiple_of(align)
+}
+
+fn inner_layout(size: usize, align: usize) -> Option<Layout> {
+	let size = user_offset(align) + size + REDZONE;
+	Layout::from_size_align(size, align.max(8)).ok()
+}
+
+#[inline]
+fn is_passthrough(layout: &Layout) -> bool {
+	layout.align() >= Mem::PAGE_SIZE as usize
+}
+
+/// fill `len` bytes from `addr` with `b`
+unsafe fn fill(addr: u64, len: usize, b: u8) {
+	ptr::write_bytes(addr as *mut u8, b, len);
+}
+
+/// returns the address of the first byte in the range that is not `b`
+unsafe fn find_bad(addr: u64, len: usize, b: u8) -> Option<u64> {
+	(0..len as u64)
+		.map(|i| addr + i)
+		.find(|a| *(*a as *const u8) != b)
+}
+
+/// walk the frame pointer chain (frame pointers are always on for our target)
+/// and record the return addresses.
+#[inline(always)]
+fn backtrace(out: &mut [u64]) {
+	let mut rbp: u64;
+	unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+	for slot in out.iter_mut() {
+		if rbp < Mem::ID_MAP_START || rbp & 0x7 != 0 {
+			break;
+		}
+		let (next, ret) =
+			unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+		*slot = ret;
+		// the stack grows down, so the chain must go upwards
+		if next <= rbp || next - rbp > Mem::KERNEL_STACK_SIZE {
+			break;
+		}
+		rbp = next;
+	}
+}
+
+struct Quarantine {
+	/// ring buffer of the freed blocks (user address)
+	blocks: [u64; QUARANTINE_CAP],
+	head: usize,
+	len: usize,
+	bytes: usize,
+}
+
+struct DebugState {
+	/// head of the live list
+	live: u64,
+	nr_live: usize,
+	quarantine: Quarantine,
+}
+
+pub struct DebugHeap {
+	heap: LockedHeap,
+	state: Mutex<DebugState>,
+}
+
+/// a summary of [DebugHeap::check]
+pub struct HeapCheck {
+	pub nr_live: usize,
+	pub nr_quarantined: usize,
+	pub nr_bad: usize,
+}
+
+impl DebugHeap {
+	pub const fn empty() -> Self {
+		Self {
+			heap: LockedHeap::empty(),
+			state: Mutex::new(DebugState {
+				live: 0,
+				nr_live: 0,
+				quarantine: Quarantine {
+					blocks: [0; QUARANTINE_CAP],
+					head: 0,
+					len: 0,
+					bytes: 0,
+				},
+			}),
+		}
+	}
+
+	/// check all live and quarantined blocks, corrupted blocks are reported on
+	/// the serial console.
+	pub fn check(&self) -> HeapCheck {
+		let st = self.state.lock();
+		let mut nr_bad = 0;
+		let mut curr = st.live;
+		while curr != 0 {
+			let hdr = unsafe { &*(curr as *const BlockHdr) };
+			if let Err(e) = unsafe { check_live(hdr) } {
+				report(hdr, e.0, e.1);
+				nr_bad += 1;
+			}
+			curr = hdr.next;
+		}
+		let q = &st.quarantine;
+		for i in 0..q.len {
+			let user = q.blocks[(q.head + i) % QUARANTINE_CAP];
+			let hdr = unsafe { BlockHdr::from_user(user) };
+			if let Err(e) = unsafe { check_freed(hdr) } {
+				report(hdr, e.0, e.1);
+				nr_bad += 1;
+			}
+		}
+		HeapCheck {
+			nr_live: st.nr_live,
+			nr_quarantined: q.len,
+			nr_bad,
+		}
+	}
+
+	/// verify and release the oldest quarantined block
+	unsafe fn evict_one(&self, st: &mut DebugState) {
+		let q = &mut st.quarantine;
+		let user = q.blocks[q.head];
+		q.head = (q.head + 1) % QUARANTINE_CAP;
+		q.len -= 1;
+		let hdr = BlockHdr::from_user(user);
+		q.bytes -= hdr.size;
+		if let Err(e) = check_freed(hdr) {
+			corrupted(hdr, e.0, e.1);
+		}
+		let (base, layout) = (hdr.base(), hdr.inner_layout());
+		self.heap.dealloc(base as *mut u8, layout);
+	}
+}
+
+/// the corruption and its address
+type Corruption = (&'static str, u64);
+
+unsafe fn check_redzones(hdr: &BlockHdr) -> Result<(), Corruption> {
+	let user = hdr.user();
+	if let Some(a) = find_bad(user - REDZONE as u64, REDZONE, REDZONE_BYTE) {
+		return Err(("buffer underflow", a));
+	}
+	let tail = hdr.base() + hdr.inner_layout().size() as u64;
+	let end = user + hdr.size as u64;
+	if let Some(a) = find_bad(end, (tail - end) as usize, REDZONE_BYTE) {
+		return Err(("buffer overflow", a));
+	}
+	Ok(())
+}
+
+unsafe fn check_live(hdr: &BlockHdr) -> Result<(), Corruption> {
+	if hdr.magic != MAGIC_LIVE {
+		return Err(("bad block header", hdr as *const _ as u64));
+	}
+	check_redzones(hdr)
+}
+
+unsafe fn check_freed(hdr: &BlockHdr) -> Result<(), Corruption> {
+	if hdr.magic != MAGIC_FREE {
+		return Err(("bad block header", hdr as *const _ as u64));
+	}
+	check_redzones(hdr)?;
+	if let Some(a) = find_bad(hdr.user(), hdr.size, POISON_FREE) {
+		return Err(("write after free", a));
+	}
+	Ok(())
+}
+
+fn report(hdr: &BlockHdr, what: &str, addr: u64) {
+	sprintln!("---- HEAP CORRUPTION: {} @ {:#X} ----", what, addr);
+	sprintln!(
+		"block {:#X}, size {}, align {}",
+		hdr.user(),
+		hdr.size,
+		hdr.align
+	);
+	sprint!("allocated at:");
+	for s in hdr.site.iter().take_while(|s| **s != 0) {
+		sprint!(" {:#X}", s);
+	}
+	sprintln!();
+}
+
+fn corrupted(hdr: &BlockHdr, what: &str, addr: u64) -> ! {
+	report(hdr, what, addr);
+	panic!("heap corruption: {} @ {:#X}", what, addr);
+}
+
+unsafe impl GlobalAlloc for DebugHeap {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		if is_passthrough(&layout) {
+			return self.heap.alloc(layout);
+		}
+		let Some(inner) = inner_layout(layout.size(), layout.align()) else {
+			return ptr::null_mut();
+		};
+		let mut st = self.state.lock();
+		let mut base = self.heap.alloc(inner);
+		// memory may be held by the quarantine
+		while base.is_null() && st.quarantine.len > 0 {
+			self.evict_one(&mut st);
+			base = self.heap.alloc(inner);
+		}
+		if base.is_null() {
+			return base;
+		}
+		let offset = user_offset(layout.align());
+		let user = base as u64 + offset as u64;
+		let hdr = BlockHdr::from_user(user);
+		*hdr = BlockHdr {
+			magic: MAGIC_LIVE,
+			size: layout.size(),
+			align: layout.align(),
+			offset,
+			prev: 0,
+			next: st.live,
+			site: [0; NR_SITES],
+		};
+		backtrace(&mut hdr.site);
+		if st.live != 0 {
+			(*(st.live as *mut BlockHdr)).prev = hdr as *const _ as u64;
+		}
+		st.live = hdr as *const _ as u64;
+		st.nr_live += 1;
+		fill(user - REDZONE as u64, REDZONE, REDZONE_BYTE);
+		fill(user, layout.size(), POISON_INUSE);
+		let end = user + layout.size() as u64;
+		let tail = base as u64 + inner.size() as u64;
+		fill(end, (tail - end) as usize, REDZONE_BYTE);
+		return user as *mut u8;
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		if is_passthrough(&layout) {
+			return self.heap.dealloc(ptr, layout);
+		}
+		let mut st = self.state.lock();
+		let hdr = BlockHdr::from_user(ptr as u64);
+		if hdr.magic == MAGIC_FREE {
+			corrupted(hdr, "double free", ptr as u64);
+		}
+		if let Err(e) = check_live(hdr) {
+			corrupted(hdr, e.0, e.1);
+		}
+		if hdr.size != layout.size() || hdr.align != layout.align() {
+			corrupted(hdr, "free with mismatching layout", ptr as u64);
+		}
+		// unlink from the live list
+		if hdr.prev != 0 {
+			(*(hdr.prev as *mut BlockHdr)).next = hdr.next;
+		} else {
+			st.live = hdr.next;
+		}
+		if hdr.next != 0 {
+			(*(hdr.next as *mut BlockHdr)).prev = hdr.prev;
+		}
+		st.nr_live -= 1;
+		hdr.magic = MAGIC_FREE;
+		fill(ptr as u64, hdr.size, POISON_FREE);
+		// park the block in the quarantine
+		while st.quarantine.len == QUARANTINE_CAP
+			|| (st.quarantine.len > 0
+				&& st.quarantine.bytes + hdr.size > QUARANTINE_BYTES)
+		{
+			self.evict_one(&mut st);
+		}
+		let q = &mut st.quarantine;
+		q.blocks[(q.head + q.len) % QUARANTINE_CAP] = ptr as u64;
+		q.len += 1;
+		q.bytes += hdr.size;
+	}
+}
+
+/// so that the wrapper can be used like the plain [LockedHeap]
+impl Deref for DebugHeap {
+	type Target = LockedHeap;
+	fn deref(&self) -> &LockedHeap { &self.heap }
+}
@@ -0,0 +1,258 @@
+//! DMA buffers for device drivers: physically contiguous memory with a known
+//! bus address. There is no IOMMU, the bus address is the physical address.
+//!
+//! Buffers come from the kernel heap, which sits in the identically mapped
+//! window, so every heap block is physically contiguous. Devices that can only
+//! address low memory (e.g. ISA DMA below 16 MiB) are served from a small pool
+//! reserved at boot, if the heap has memory down there.
+//!
+//! Streaming buffers are accessed through the (write back) identical mapping,
+//! which is fine for devices that snoop the caches (bus master DMA on x86
+//! does). Coherent buffers (e.g. descriptor rings shared with the device) come
+//! from a pool that is uncached in every kernel mapping of its frames, so
+//! there is no cached alias that could hold or write back stale lines.
+use crate::arch::x86_64::paging::uncache_kernel_2m;
+use crate::defs::*;
+use crate::mm::flush_tlb;
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::arch::asm;
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+/// size of the low memory pool
+const LOW_POOL_SIZE: u64 = Mem::M;
+
+/// pool for devices that can't reach all of the heap, empty if the heap
+/// doesn't have memory below [DmaConstraints::ISA]'s limit
+static LOW_POOL: Mutex<Heap> = Mutex::new(Heap::empty());
+
+/// size of the coherent pool: a single 2M page, uncached
+const COHERENT_POOL_SIZE: u64 = 2 * Mem::M;
+
+/// uncached memory for coherent buffers, empty if it couldn't be set up
+static COHERENT_POOL: Mutex<Heap> = Mutex::new(Heap::empty());
+
+/// where a buffer comes from
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Pool {
+	Heap,
+	Low,
+	Coherent,
+}
+
+/// what a device can address
+#[derive(Copy, Clone, Debug)]
+pub struct DmaConstraints {
+	/// the buffer must end at or below this bus address
+	pub limit: u64,
+	/// alignment of the bus address (power of 2)
+	pub align: u64,
+	/// the buffer must not cross a multiple of this (power of 2), 0 for none
+	pub boundary: u64,
+}
+
+impl DmaConstraints {
+	/// legacy ISA DMA: below 16 MiB, must not cross 64K
+	pub const ISA: Self = Self {
+		limit: 16 * Mem::M,
+		align: 16,
+		boundary: 64 * Mem::K,
+	};
+	/// 32-bit devices
+	pub const DMA32: Self = Self {
+		limit: 4 * Mem::G,
+		align: 16,
+		boundary: 0,
+	};
+	/// no restriction
+	pub const ANY: Self = Self {
+		limit: u64::MAX,
+		align: 16,
+		boundary: 0,
+	};
+}
+
+/// a DMA buffer, give it back with [free]
+#[derive(Debug)]
+pub struct DmaBuf {
+	/// where the cpu accesses the buffer (the identical mapping)
+	pub va: u64,
+	/// the address to program into the device
+	pub bus: u64,
+	pub size: u64,
+	/// the buffer is uncached
+	pub coherent: bool,
+	layout: Layout,
+	pool: Pool,
+}
+
+/// reserve the pools, call after the heap is initialized and the low boot
+/// mapping (another cached alias) is gone
+pub fn init() {
+	init_low_pool();
+	init_coherent_pool();
+}
+
+fn init_low_pool() {
+	let layout = Layout::from_size_align(
+		LOW_POOL_SIZE as usize,
+		Mem::PAGE_SIZE as usize,
+	)
+	.unwrap();
+	let va = unsafe { alloc_zeroed(layout) } as u64;
+	if va == 0 {
+		println!("[init] dma: can't reserve low pool");
+		return;
+	}
+	let pa = V2P(va).unwrap();
+	if pa + LOW_POOL_SIZE > DmaConstraints::ISA.limit {
+		unsafe { dealloc(va as *mut u8, layout) };
+		println!("[init] dma: no low memory for the pool");
+		return;
+	}
+	unsafe { LOW_POOL.lock().init(va as *mut u8, LOW_POOL_SIZE as usize) };
+	println!(
+		"[init] dma: low pool @ {:#X} - {:#X}",
+		pa,
+		pa + LOW_POOL_SIZE
+	);
+}
+
+/// take a 2M page from the heap and make it uncached. The caches may still
+/// hold lines of it from the write back mapping, they are written back and
+/// invalidated after the tlb flush.
+fn init_coherent_pool() {
+	let layout = Layout::from_size_align(
+		COHERENT_POOL_SIZE as usize,
+		COHERENT_POOL_SIZE as usize,
+	)
+	.unwrap();
+	let va = unsafe { alloc_zeroed(layout) } as u64;
+	if va == 0 {
+		println!("[init] dma: can't reserve coherent pool");
+		return;
+	}
+	let pa = V2P(va).unwrap();
+	if !uncache_kernel_2m(pa) {
+		// a partly split mapping is still write back, just not ours
+		println!("[init] dma: can't map coherent pool uncached");
+		return;
+	}
+	flush_tlb();
+	unsafe { asm!("wbinvd") };
+	unsafe {
+		COHERENT_POOL
+			.lock()
+			.init(va as *mut u8, COHERENT_POOL_SIZE as usize)
+	};
+	println!(
+		"[init] dma: coherent pool @ {:#X} - {:#X}",
+		pa,
+		pa + COHERENT_POOL_SIZE
+	);
+}
+
+/// allocate a zeroed DMA buffer of `size` bytes that satisfies `c`. A
+/// `coherent` buffer is uncached and page granular.
+pub fn alloc(
+	size: u64,
+	c: &DmaConstraints,
+	coherent: bool,
+) -> Result<DmaBuf, &'static str> {
+	if size == 0 {
+		return Err("empty dma buffer");
+	}
+	let layout = layout_for(size, c, coherent)?;
+	let (va, pool) = if coherent {
+		(alloc_coherent(layout, c.limit)?, Pool::Coherent)
+	} else {
+		alloc_block(layout, c.limit)?
+	};
+	Ok(DmaBuf {
+		va,
+		bus: V2P(va).unwrap(),
+		size,
+		coherent,
+		layout,
+		pool,
+	})
+}
+
+/// give the buffer back. The device must be done with it.
+pub fn free(buf: DmaBuf) { free_block(buf.va, buf.layout, buf.pool); }
+
+/// the heap layout for a buffer. A block aligned to a power of 2 at least its
+/// size never crosses a boundary larger than that.
+fn layout_for(
+	size: u64,
+	c: &DmaConstraints,
+	coherent: bool,
+) -> Result<Layout, &'static str> {
+	let mut size = size;
+	let mut align = u64::max(c.align, 1);
+	if coherent {
+		size = roundup_4k(size);
+		align = u64::max(align, Mem::PAGE_SIZE);
+	}
+	if c.boundary != 0 {
+		if size > c.boundary {
+			return Err("dma buffer larger than boundary");
+		}
+		align = u64::max(align, size.next_power_of_two());
+	}
+	Layout::from_size_align(size as usize, align as usize)
+		.map_err(|_| "bad dma alignment")
+}
+
+/// allocate from the heap (reclaiming user pages if needed) and fall back to
+/// the low pool if the block is above `limit`.
+fn alloc_block(
+	layout: Layout,
+	limit: u64,
+) -> Result<(u64, Pool), &'static str> {
+	let va = unsafe { alloc_zeroed(layout) } as u64;
+	if va != 0 {
+		if fits(va, layout, limit) {
+			return Ok((va, Pool::Heap));
+		}
+		unsafe { dealloc(va as *mut u8, layout) };
+	}
+	let va = match LOW_POOL.lock().allocate_first_fit(layout) {
+		Ok(p) => p.as_ptr() as u64,
+		Err(_) => return Err("out of dma memory"),
+	};
+	if !fits(va, layout, limit) {
+		free_block(va, layout, Pool::Low);
+		return Err("no dma memory below limit");
+	}
+	unsafe { (va as *mut u8).write_bytes(0, layout.size()) };
+	Ok((va, Pool::Low))
+}
+
+fn alloc_coherent(layout: Layout, limit: u64) -> Result<u64, &'static str> {
+	let va = match COHERENT_POOL.lock().allocate_first_fit(layout) {
+		Ok(p) => p.as_ptr() as u64,
+		Err(_) => return Err("out of coherent dma memory"),
+	};
+	if !fits(va, layout, limit) {
+		free_block(va, layout, Pool::Coherent);
+		return Err("no coherent dma memory below limit");
+	}
+	unsafe { (va as *mut u8).write_bytes(0, layout.size()) };
+	Ok(va)
+}
+
+#[inline]
+fn fits(va: u64, layout: Layout, limit: u64) -> bool {
+	V2P(va).unwrap() + layout.size() as u64 <= limit
+}
+
+fn free_block(va: u64, layout: Layout, pool: Pool) {
+	let p = NonNull::new(va as *mut u8).unwrap();
+	match pool {
+		Pool::Heap => unsafe { dealloc(va as *mut u8, layout) },
+		Pool::Low => unsafe { LOW_POOL.lock().deallocate(p, layout) },
+		Pool::Coherent => unsafe { COHERENT_POOL.lock().deallocate(p, layout) },
+	}
+}
@@ -0,0 +1,193 @@
+//! per-frame bookkeeping. Every 4K physical frame managed by the kernel heap
+//! has a [FrameDesc] in the global [FRAME_TABLE], indexed by its PFN (physical
+//! address >> 12). The heap allocator itself knows nothing about frames, so a
+//! frame only gets an owner when it's handed out as a whole page, e.g. via
+//! [crate::mm::allocate_4k] or the kernel stack allocator. Frames that host
+//! ordinary heap objects (Vec, Box ...) remain [FrameOwner::NONE].
+use crate::defs::*;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::ops::Range;
+use spin::Mutex;
+
+pub static FRAME_TABLE: Mutex<FrameTable> = Mutex::new(FrameTable::new());
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameOwner {
+	/// not handed out as a page (free, or hosting small heap objects)
+	NONE = 0,
+	PAGETABLE,
+	ANON,
+	HEAP,
+	STACK,
+	FILE,
+	SHM,
+}
+
+impl FrameOwner {
+	pub const COUNT: usize = 7;
+	pub const ALL: [FrameOwner; Self::COUNT] = [
+		Self::NONE,
+		Self::PAGETABLE,
+		Self::ANON,
+		Self::HEAP,
+		Self::STACK,
+		Self::FILE,
+		Self::SHM,
+	];
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::NONE => "none",
+			Self::PAGETABLE => "pagetable",
+			Self::ANON => "user anon",
+			Self::HEAP => "kernel heap",
+			Self::STACK => "kstack",
+			Self::FILE => "file",
+			Self::SHM => "shm",
+		}
+	}
+}
+
+bitflags! {
+	#[derive(Copy, Clone, Debug)]
+	pub struct FrameFlags: u8 {
+		const NONE   = 0;
+		/// the frame was zeroed upon allocation
+		const ZEROED = 1 << 0;
+		/// the frame must not be moved or reclaimed
+		const PINNED = 1 << 1;
+		/// the frame is on the reclaim LRU list
+		const LRU    = 1 << 2;
+	}
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct FrameDesc {
+	/// number of references (mappings) to this frame. 0 means the frame is not
+	/// owned as a page.
+	pub refcount: u32,
+	pub owner: FrameOwner,
+	pub flags: FrameFlags,
+	/// (pt_root, va) of the user mapping, used by page reclaim to find the PTE
+	/// of the frame. Only meaningful while the frame is mapped once.
+	pub rmap: Option<(u64, u64)>,
+}
+
+impl FrameDesc {
+	pub const fn new() -> Self {
+		Self {
+			refcount: 0,
+			owner: FrameOwner::NONE,
+			flags: FrameFlags::NONE,
+			rmap: None,
+		}
+	}
+}
+
+pub struct FrameTable {
+	/// pfn of descs[0]
+	base_pfn: u64,
+	descs: Vec<FrameDesc>,
+	/// number of frames per owner, indexed by `FrameOwner as usize`
+	owned: [u64; FrameOwner::COUNT],
+}
+
+impl FrameTable {
+	pub const fn new() -> Self {
+		Self {
+			base_pfn: 0,
+			descs: Vec::new(),
+			owned: [0; FrameOwner::COUNT],
+		}
+	}
+
+	/// allocate the descriptor array for the physical range. The array itself
+	/// lives on the heap, so this must be called after the heap allocator is
+	/// initialized.
+	pub fn init(&mut self, pr: &Range<u64>) {
+		let start = roundup_4k(pr.start) >> Mem::PAGE_SHIFT;
+		let end = rounddown_4k(pr.end) >> Mem::PAGE_SHIFT;
+		self.base_pfn = start;
+		self.descs = Vec::with_capacity((end - start) as usize);
+		self.descs.resize((end - start) as usize, FrameDesc::new());
+	}
+
+	#[inline]
+	pub fn nr_frames(&self) -> u64 { self.descs.len() as u64 }
+
+	#[inline]
+	pub fn get(&self, pa: u64) -> Option<&FrameDesc> {
+		let idx = (pa >> Mem::PAGE_SHIFT).checked_sub(self.base_pfn)?;
+		self.descs.get(idx as usize)
+	}
+
+	#[inline]
+	pub fn get_mut(&mut self, pa: u64) -> Option<&mut FrameDesc> {
+		let idx = (pa >> Mem::PAGE_SHIFT).checked_sub(self.base_pfn)?;
+		self.descs.get_mut(idx as usize)
+	}
+
+	/// record `n` consecutive frames starting from `pa` as being handed out to
+	/// `owner`, with an initial refcount of 1.
+	pub fn mark_alloc(
+		&mut self,
+		pa: u64,
+		n: u64,
+		owner: FrameOwner,
+		flags: FrameFlags,
+	) {
+		for i in 0..n {
+			if let Some(d) = self.get_mut(pa + i * Mem::PAGE_SIZE) {
+				debug_assert_eq!(d.refcount, 0, "frame {:#X} in use", pa);
+				d.refcount = 1;
+				d.owner = owner;
+				d.flags = flags;
+				self.owned[owner as usize] += 1;
+			}
+		}
+	}
+
+	/// reverse of [Self::mark_alloc], regardless of the refcount.
+	pub fn mark_free(&mut self, pa: u64, n: u64) {
+		for i in 0..n {
+			let Some(d) = self.get_mut(pa + i * Mem::PAGE_SIZE) else {
+				continue;
+			};
+			if d.refcount == 0 {
+				continue;
+			}
+			let owner = d.owner;
+			*d = FrameDesc::new();
+			self.owned[owner as usize] -= 1;
+		}
+	}
+
+	/// take another reference to an owned frame, returns the new refcount
+	pub fn ref_inc(&mut self, pa: u64) -> u32 {
+		let d = self.get_mut(pa).expect("ref_inc: frame not managed");
+		debug_assert_ne!(d.refcount, 0);
+		d.refcount += 1;
+		d.refcount
+	}
+
+	/// drop a reference to an owned frame, returns the remaining refcount.
+	/// When this reaches 0 the descriptor is released and the caller is
+	/// responsible to give the frame back to the allocator.
+	pub fn ref_dec(&mut self, pa: u64) -> u32 {
+		let d = self.get_mut(pa).expect("ref_dec: frame not managed");
+		debug_assert_ne!(d.refcount, 0);
+		if d.refcount == 1 {
+			self.mark_free(pa, 1);
+			return 0;
+		}
+		d.refcount -= 1;
+		d.refcount
+	}
+
+	/// number of frames handed out to `owner`
+	#[inline]
+	pub fn nr_owned(&self, owner: FrameOwner) -> u64 {
+		self.owned[owner as usize]
+	}
+}
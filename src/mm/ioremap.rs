@@ -0,0 +1,75 @@
+//! map physical MMIO ranges into the kernel. The identical mapping covers all
+//! of the physical address space too, but it's cached (1G pages, write back)
+//! and must not be used for device registers.
+//!
+//! Mappings live in the [Mem::IOREMAP_START] window of the shared kernel half,
+//! so they are visible in every address space.
+use crate::arch::x86_64::paging::pat::{self, CacheMode};
+use crate::arch::x86_64::paging::PTEFlags;
+use crate::arch::x86_64::paging::{kernel_root, map_page_to, unmap_page};
+use crate::defs::*;
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+
+/// va => size (page granular) of the mappings in the ioremap window
+static IOREMAP: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+
+/// map `size` bytes of MMIO at physical address `pa` with caching `mode`.
+/// Returns the virtual address of `pa` (which doesn't have to be page aligned).
+pub fn ioremap(
+	pa: u64,
+	size: u64,
+	mode: CacheMode,
+) -> Result<u64, &'static str> {
+	if size == 0 {
+		return Err("empty ioremap");
+	}
+	let start = rounddown_4k(pa);
+	let len = roundup_4k(pa + size) - start;
+	let mut maps = IOREMAP.lock();
+	let va = find_free(&maps, len).ok_or("ioremap window exhausted")?;
+	let flags = PTEFlags::PRESENT
+		| PTEFlags::WRITABLE
+		| PTEFlags::NE
+		| pat::pte_flags(mode);
+	let mut off = 0;
+	while off < len {
+		if !map_page_to(kernel_root(), va + off, start + off, flags) {
+			unmap_range(va, off);
+			return Err("can't map io range");
+		}
+		off += Mem::PAGE_SIZE;
+	}
+	maps.insert(va, len);
+	Ok(va + (pa - start))
+}
+
+/// remove a mapping created by [ioremap], `va` is the address it returned.
+pub fn iounmap(va: u64) {
+	let va = rounddown_4k(va);
+	let Some(len) = IOREMAP.lock().remove(&va) else {
+		println!("iounmap: {:#X} is not mapped", va);
+		return;
+	};
+	unmap_range(va, len);
+}
+
+/// first fit, with a guard page after every mapping
+fn find_free(maps: &BTreeMap<u64, u64>, len: u64) -> Option<u64> {
+	let mut va = Mem::IOREMAP_START;
+	for (&start, &size) in maps.iter() {
+		if va + len <= start {
+			break;
+		}
+		va = start + size + Mem::PAGE_SIZE;
+	}
+	(va + len <= Mem::IOREMAP_END).then_some(va)
+}
+
+fn unmap_range(va: u64, len: u64) {
+	let mut off = 0;
+	while off < len {
+		unmap_page(kernel_root(), va + off);
+		off += Mem::PAGE_SIZE;
+	}
+}
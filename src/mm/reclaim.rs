@@ -0,0 +1,238 @@
+//! page reclaim. User pages with a single mapping (anonymous memory and private
+//! copies of file pages) are put on an LRU list when they are mapped. When the
+//! heap runs out of memory ([ReclaimHeap] wraps the global allocator), [reclaim]
+//! walks the list with a second chance on the accessed bit and evicts the cold
+//! pages:
+//! - clean file pages are simply dropped, demand paging reads them again from
+//!   the ramfs.
+//! - anonymous and dirty pages are compressed into [ZRAM], the PTE is replaced
+//!   by a swap entry, which is resolved by [swap_in] on the next fault.
+use crate::arch::x86_64::paging::{get_pte, PTEFlags};
+use crate::defs::*;
+use crate::machine::interrupt::{irq_restore, irq_save};
+use crate::mm::frame::{FrameFlags, FrameOwner, FRAME_TABLE};
+use crate::mm::zram::ZRAM;
+use crate::mm::{allocate_4k, free_4k, invlpg_root};
+use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::collections::VecDeque;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// physical addresses of reclaimable frames, coldest first. Entries may be
+/// stale (the frame was freed or remapped), they are checked against the frame
+/// table when scanned.
+pub static LRU: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::new());
+
+/// number of pages to reclaim at once when an allocation fails
+pub const RECLAIM_BATCH: usize = 16;
+/// LRU entries scanned with interrupts disabled at a time
+const SCAN_CHUNK: usize = 8;
+/// LRU entries scanned at most per [reclaim] call
+const SCAN_MAX: usize = 1024;
+/// number of live [NoReclaim] guards: somebody allocates while holding a lock
+/// that [reclaim] takes (FRAME_TABLE, LRU or ZRAM), a failed allocation must
+/// not reclaim then.
+static NO_RECLAIM: AtomicUsize = AtomicUsize::new(0);
+
+/// while alive, failed allocations don't reclaim. Take one before locking
+/// FRAME_TABLE, LRU or ZRAM to allocate under them.
+pub struct NoReclaim {}
+
+pub fn no_reclaim() -> NoReclaim {
+	NO_RECLAIM.fetch_add(1, Ordering::Relaxed);
+	NoReclaim {}
+}
+
+impl Drop for NoReclaim {
+	fn drop(&mut self) { NO_RECLAIM.fetch_sub(1, Ordering::Relaxed); }
+}
+
+/// a heap that reclaims user pages and tries again when it's exhausted
+pub struct ReclaimHeap<A>(pub A);
+
+impl<A> Deref for ReclaimHeap<A> {
+	type Target = A;
+	fn deref(&self) -> &A { &self.0 }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ReclaimHeap<A> {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		retry(|| self.0.alloc(layout))
+	}
+
+	unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+		retry(|| self.0.alloc_zeroed(layout))
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		self.0.dealloc(ptr, layout)
+	}
+
+	unsafe fn realloc(
+		&self,
+		ptr: *mut u8,
+		layout: Layout,
+		new_size: usize,
+	) -> *mut u8 {
+		// a failed realloc leaves the block alone
+		retry(|| self.0.realloc(ptr, layout, new_size))
+	}
+}
+
+/// call `f` until it succeeds or nothing more can be reclaimed
+fn retry(f: impl Fn() -> *mut u8) -> *mut u8 {
+	loop {
+		let p = f();
+		if !p.is_null() || reclaim(RECLAIM_BATCH) == 0 {
+			return p;
+		}
+	}
+}
+
+/// record the user mapping of the frame `pa` at `va` and make it reclaimable.
+/// Frames the LRU can't track (e.g. when we are out of memory) are simply not
+/// reclaimable.
+pub fn lru_add(pa: u64, pt_root: u64, va: u64) {
+	// the LRU may grow below
+	let _nr = no_reclaim();
+	let mut ft = FRAME_TABLE.lock();
+	let Some(d) = ft.get_mut(pa) else {
+		return;
+	};
+	d.rmap = Some((pt_root, va));
+	if d.flags.contains(FrameFlags::LRU) {
+		return;
+	}
+	let mut lru = LRU.lock();
+	if lru.try_reserve(1).is_err() {
+		return;
+	}
+	lru.push_back(pa);
+	d.flags |= FrameFlags::LRU;
+}
+
+enum Scan {
+	/// the page is gone
+	Evicted,
+	/// the page stays on the LRU
+	Keep,
+	/// not (or no longer) reclaimable
+	Drop,
+}
+
+/// try to free `nr` frames by evicting user pages. Returns the number of frames
+/// freed, 0 if called under a [NoReclaim] guard. The LRU is scanned in small
+/// chunks with interrupts disabled (the page fault handler takes the same
+/// locks), interrupts may come in between.
+pub fn reclaim(nr: usize) -> usize {
+	let may_reclaim = || NO_RECLAIM.load(Ordering::Relaxed) == 0;
+	let irq = irq_save();
+	// every entry gets a second chance
+	let mut budget = 0;
+	if may_reclaim() {
+		budget = usize::min(LRU.lock().len() * 2, SCAN_MAX);
+	}
+	irq_restore(irq);
+	let mut freed = 0;
+	while freed < nr && budget > 0 {
+		let chunk = usize::min(budget, SCAN_CHUNK);
+		budget -= chunk;
+		let irq = irq_save();
+		if !may_reclaim() {
+			irq_restore(irq);
+			break;
+		}
+		// allocations in the chunk (e.g. by zram) must not reclaim again
+		let nr_guard = no_reclaim();
+		let mut empty = false;
+		for _ in 0..chunk {
+			if freed == nr {
+				break;
+			}
+			let Some(pa) = LRU.lock().pop_front() else {
+				empty = true;
+				break;
+			};
+			match unsafe { try_evict(pa) } {
+				Scan::Evicted => freed += 1,
+				Scan::Keep => LRU.lock().push_back(pa),
+				Scan::Drop => {}
+			}
+		}
+		drop(nr_guard);
+		irq_restore(irq);
+		if empty {
+			break;
+		}
+	}
+	return freed;
+}
+
+unsafe fn try_evict(pa: u64) -> Scan {
+	let mut ft = FRAME_TABLE.lock();
+	let Some(d) = ft.get_mut(pa) else {
+		return Scan::Drop;
+	};
+	let reclaimable = d.refcount == 1
+		&& !d.flags.contains(FrameFlags::PINNED)
+		&& matches!(d.owner, FrameOwner::ANON | FrameOwner::FILE);
+	let pte = d
+		.rmap
+		.filter(|_| reclaimable)
+		.and_then(|(pt_root, va)| Some((get_pte(pt_root, va)?, pt_root, va)))
+		.filter(|(pte, _, _)| {
+			pte.flags().contains(PTEFlags::PRESENT) && pte.addr() == pa
+		});
+	let Some((pte, pt_root, va)) = pte else {
+		d.flags.remove(FrameFlags::LRU);
+		return Scan::Drop;
+	};
+	if pte.flags().contains(PTEFlags::ACCESSED) {
+		pte.entry &= !PTEFlags::ACCESSED.bits();
+		invlpg_root(pt_root, va);
+		return Scan::Keep;
+	}
+	let dirty = pte.flags().contains(PTEFlags::DIRTY);
+	if d.owner == FrameOwner::FILE && !dirty {
+		pte.set_unused();
+	} else {
+		match ZRAM.lock().store(P2V(pa).unwrap()) {
+			Ok(slot) => pte.set_swap(slot),
+			Err(_) => return Scan::Keep,
+		}
+	}
+	invlpg_root(pt_root, va);
+	d.flags.remove(FrameFlags::LRU);
+	drop(ft);
+	free_4k(P2V(pa).unwrap());
+	return Scan::Evicted;
+}
+
+/// bring the page in swap `slot` back into a fresh frame and map it at `va`.
+/// The page is mapped dirty because it no longer matches any backing file.
+pub fn swap_in(
+	pt_root: u64,
+	va: u64,
+	slot: u64,
+	flags: PTEFlags,
+	owner: FrameOwner,
+) -> Result<(), &'static str> {
+	let new = allocate_4k(owner);
+	if new == 0 {
+		return Err("out of memory");
+	}
+	if let Err(e) = ZRAM.lock().load(slot, new) {
+		unsafe { free_4k(new) };
+		return Err(e);
+	}
+	let pa = V2P(new).unwrap();
+	let Some(pte) = get_pte(pt_root, va) else {
+		unsafe { free_4k(new) };
+		return Err("swap entry gone");
+	};
+	pte.set(pa, flags | PTEFlags::DIRTY);
+	invlpg_root(pt_root, va);
+	lru_add(pa, pt_root, va);
+	Ok(())
+}
@@ -0,0 +1,208 @@
+//! named shared memory segments. A segment is a list of frames that can be
+//! mapped into multiple address spaces, possibly at different addresses.
+//!
+//! Lifetime: the segment itself holds one reference on each of its frames, and
+//! every mapping of a page takes another one. The segment is destroyed once it
+//! is unlinked (the name is gone) and no longer attached anywhere; the frames
+//! are given back when the last mapping is removed.
+use crate::arch::x86_64::paging::{map_page_to, unmap_page, PTEFlags};
+use crate::defs::*;
+use crate::mm::frame::{FrameOwner, FRAME_TABLE};
+use crate::mm::vmm::{VMArea, VMFlags, VMMan, VMPerms, VMType};
+use crate::mm::{allocate_4k_zeroed, free_4k};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
+
+pub static SHM_REGISTRY: Mutex<ShmRegistry> = Mutex::new(ShmRegistry::new());
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShmError {
+	/// no segment with that name or id
+	NoSegment,
+	/// out of frames or page table memory
+	NoMemory,
+	/// no free address range large enough for the segment
+	NoSpace,
+	/// a new segment must have a size
+	ZeroSize,
+	/// the address is not page aligned, not user space or already in use
+	BadAddress,
+	/// there is no shm mapping at the address
+	NotMapped,
+}
+
+pub struct ShmSegment {
+	pub name: String,
+	pub size: u64,
+	/// physical address of the frames backing the segment
+	frames: Vec<u64>,
+	/// number of VMAs mapping this segment
+	pub nattch: u32,
+	/// the name was removed, the segment goes away with the last detach
+	pub unlinked: bool,
+}
+
+impl ShmSegment {
+	/// drop the references held by the segment itself
+	fn release(&mut self) {
+		for pa in self.frames.drain(..) {
+			unsafe { free_4k(P2V(pa).unwrap()) };
+		}
+	}
+}
+
+pub struct ShmRegistry {
+	next_id: u32,
+	pub segs: BTreeMap<u32, ShmSegment>,
+}
+
+impl ShmRegistry {
+	pub const fn new() -> Self { Self { next_id: 1, segs: BTreeMap::new() } }
+
+	fn lookup(&self, name: &str) -> Option<u32> {
+		self.segs
+			.iter()
+			.find(|(_, s)| !s.unlinked && s.name == name)
+			.map(|(id, _)| *id)
+	}
+
+	/// destroy the segment if it's neither named nor attached
+	fn try_destroy(&mut self, id: u32) {
+		let Some(seg) = self.segs.get_mut(&id) else {
+			return;
+		};
+		if seg.unlinked && seg.nattch == 0 {
+			seg.release();
+			self.segs.remove(&id);
+		}
+	}
+}
+
+/// open the segment `name`, create it with `size` bytes if it doesn't exist
+/// (like shm_open with O_CREAT + ftruncate). Returns the segment id.
+pub fn open(name: &str, size: u64) -> Result<u32, ShmError> {
+	let mut reg = SHM_REGISTRY.lock();
+	if let Some(id) = reg.lookup(name) {
+		return Ok(id);
+	}
+	if size == 0 {
+		return Err(ShmError::ZeroSize);
+	}
+	let nr_pages = roundup_4k(size) / Mem::PAGE_SIZE;
+	let mut frames = Vec::with_capacity(nr_pages as usize);
+	for _ in 0..nr_pages {
+		let va = allocate_4k_zeroed(FrameOwner::SHM);
+		if va == 0 {
+			for pa in frames {
+				unsafe { free_4k(P2V(pa).unwrap()) };
+			}
+			return Err(ShmError::NoMemory);
+		}
+		frames.push(V2P(va).unwrap());
+	}
+	let id = reg.next_id;
+	reg.next_id += 1;
+	reg.segs.insert(
+		id,
+		ShmSegment {
+			name: String::from(name),
+			size: nr_pages * Mem::PAGE_SIZE,
+			frames,
+			nattch: 0,
+			unlinked: false,
+		},
+	);
+	Ok(id)
+}
+
+/// remove the name of the segment. Existing mappings stay valid.
+pub fn unlink(name: &str) -> Result<(), ShmError> {
+	let mut reg = SHM_REGISTRY.lock();
+	let id = reg.lookup(name).ok_or(ShmError::NoSegment)?;
+	reg.segs.get_mut(&id).unwrap().unlinked = true;
+	reg.try_destroy(id);
+	Ok(())
+}
+
+/// map the whole segment `id` into the address space (like mmap with
+/// MAP_SHARED or shmat). If `addr` is None, the kernel picks an address in the
+/// user mmap area. Returns the address of the mapping.
+pub fn map(
+	mm: &mut VMMan,
+	pt_root: u64,
+	id: u32,
+	addr: Option<u64>,
+	perms: VMPerms,
+) -> Result<u64, ShmError> {
+	let mut reg = SHM_REGISTRY.lock();
+	let seg = reg.segs.get_mut(&id).ok_or(ShmError::NoSegment)?;
+	let start = match addr {
+		Some(a) if !is_aligned_4k(a) => return Err(ShmError::BadAddress),
+		Some(a) => a,
+		None => mm.find_free(seg.size).ok_or(ShmError::NoSpace)?,
+	};
+	let range =
+		start..start.checked_add(seg.size).ok_or(ShmError::BadAddress)?;
+	if range.end > Mem::USER_END || mm.overlaps(&range) {
+		return Err(ShmError::BadAddress);
+	}
+	let vma = VMArea {
+		vm_range: range,
+		tag: seg.name.clone(),
+		user_perms: perms,
+		backing: VMType::SHM(id),
+		flags: VMFlags::NONE,
+	};
+	let mut flags = PTEFlags::PRESENT | PTEFlags::USER;
+	if perms.contains(VMPerms::W) {
+		flags |= PTEFlags::WRITABLE;
+	}
+	for (i, pa) in seg.frames.iter().enumerate() {
+		let va = start + i as u64 * Mem::PAGE_SIZE;
+		if !map_page_to(pt_root, va, *pa, flags) {
+			// roll back what's already mapped
+			unmap_pages(pt_root, start..va);
+			return Err(ShmError::NoMemory);
+		}
+		FRAME_TABLE.lock().ref_inc(*pa);
+	}
+	seg.nattch += 1;
+	mm.vmas.push(vma);
+	Ok(start)
+}
+
+/// remove the shm mapping that starts at `addr` (like munmap or shmdt).
+pub fn unmap(mm: &mut VMMan, pt_root: u64, addr: u64) -> Result<(), ShmError> {
+	let idx = mm
+		.vmas
+		.iter()
+		.position(|v| {
+			v.vm_range.start == addr && matches!(v.backing, VMType::SHM(_))
+		})
+		.ok_or(ShmError::NotMapped)?;
+	let vma = mm.vmas.remove(idx);
+	let VMType::SHM(id) = vma.backing else {
+		unreachable!();
+	};
+	unmap_pages(pt_root, vma.vm_range);
+	let mut reg = SHM_REGISTRY.lock();
+	if let Some(seg) = reg.segs.get_mut(&id) {
+		seg.nattch -= 1;
+	}
+	reg.try_destroy(id);
+	Ok(())
+}
+
+/// unmap pages and drop the references taken by the mapping
+fn unmap_pages(pt_root: u64, r: Range<u64>) {
+	let mut va = r.start;
+	while va < r.end {
+		if let Some(pa) = unmap_page(pt_root, va) {
+			unsafe { free_4k(P2V(pa).unwrap()) };
+		}
+		va += Mem::PAGE_SIZE;
+	}
+}
@@ -1,5 +1,7 @@
 //! a very simple virtual memory manager
 
+use crate::arch::x86_64::paging::{get_pte, map_vma, PTEFlags};
+use crate::defs::*;
 use alloc::string::String;
 use alloc::vec::Vec;
 use bitflags::bitflags;
@@ -12,9 +14,97 @@ pub struct VMMan {
 
 impl VMMan {
 	pub fn new() -> Self { Self { vmas: Vec::<VMArea>::new() } }
+
+	/// resident set size: number of present 4K pages in the user accessible
+	/// VMAs, looked up in the page table `pt_root`.
+	pub fn rss(&self, pt_root: u64) -> u64 {
+		let mut pages = 0;
+		for vma in &self.vmas {
+			if vma.user_perms.is_empty() {
+				continue;
+			}
+			let mut va = rounddown_4k(vma.vm_range.start);
+			while va < vma.vm_range.end {
+				if let Some(pte) = get_pte(pt_root, va) {
+					if pte.flags().contains(PTEFlags::PRESENT) {
+						pages += 1;
+					}
+				}
+				va += Mem::PAGE_SIZE;
+			}
+		}
+		pages
+	}
+
+	/// find the VMA that contains `va`
+	pub fn find(&mut self, va: u64) -> Option<&mut VMArea> {
+		self.vmas.iter_mut().find(|vma| vma.vm_range.contains(&va))
+	}
+
+	/// find a free range of `size` bytes in the user mmap area
+	pub fn find_free(&self, size: u64) -> Option<u64> {
+		let size = roundup_4k(size);
+		let mut start = Mem::USER_MMAP_START;
+		while start + size <= Mem::USER_MMAP_END {
+			let r = start..(start + size);
+			match self
+				.vmas
+				.iter()
+				.find(|v| v.vm_range.start < r.end && r.start < v.vm_range.end)
+			{
+				None => return Some(start),
+				Some(v) => start = roundup_4k(v.vm_range.end),
+			}
+		}
+		None
+	}
+
+	/// map a ramfs file read-only into the address space, at `addr` or at a
+	/// free address in the user mmap area. Page aligned parts of the file are
+	/// shared with the ramfs instead of being copied.
+	pub fn mmap_file(
+		&mut self,
+		pt_root: u64,
+		f: &'static [u8],
+		tag: &str,
+		addr: Option<u64>,
+	) -> Result<u64, &'static str> {
+		let size = f.len() as u64;
+		if size == 0 {
+			return Err("empty file");
+		}
+		let start = match addr {
+			Some(a) if !is_aligned_4k(a) => return Err("address not aligned"),
+			Some(a) => a,
+			None => self.find_free(size).ok_or("no free address range")?,
+		};
+		let vma = VMArea {
+			vm_range: start..(start + size),
+			tag: String::from(tag),
+			user_perms: VMPerms::R,
+			backing: VMType::FILE(f),
+			flags: VMFlags::NONE,
+		};
+		if vma.vm_range.end > Mem::USER_END || self.overlaps(&vma.vm_range) {
+			return Err("bad address range");
+		}
+		if !unsafe { map_vma(pt_root, &vma, true) } {
+			return Err("failed to map file");
+		}
+		self.vmas.push(vma);
+		Ok(start)
+	}
+
+	/// whether `r` overlaps any existing VMA
+	pub fn overlaps(&self, r: &Range<u64>) -> bool {
+		self.vmas
+			.iter()
+			.any(|vma| vma.vm_range.start < r.end && r.start < vma.vm_range.end)
+	}
 }
 
 bitflags! {
+	#[derive(Copy, Clone)]
 	pub struct VMPerms: u8 {
 		const NONE = 0;
 		const R = 1 << 0;
@@ -23,6 +113,15 @@ bitflags! {
 	}
 }
 
+bitflags! {
+	#[derive(Copy, Clone, Debug)]
+	pub struct VMFlags: u8 {
+		const NONE      = 0;
+		/// the VMA may be extended downwards on page faults (stack)
+		const GROWSDOWN = 1 << 0;
+	}
+}
+
 impl fmt::Debug for VMPerms {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		write!(
@@ -40,6 +139,7 @@ pub struct VMArea {
 	pub tag: String,
 	pub user_perms: VMPerms,
 	pub backing: VMType,
+	pub flags: VMFlags,
 }
 
 impl fmt::Debug for VMArea {
@@ -59,6 +159,8 @@ impl fmt::Debug for VMArea {
 pub enum VMType {
 	ANOM,
 	FILE(&'static [u8]),
+	/// shared memory segment, identified by the shm id
+	SHM(u32),
 	// NONE for device memory mappings
 	NONE,
 }
@@ -71,6 +173,7 @@ impl fmt::Debug for VMType {
 			match self {
 				Self::ANOM => "ANOM",
 				Self::FILE(_) => "FILE",
+				Self::SHM(_) => "SHM",
 				Self::NONE => "DEV",
 			},
 		)
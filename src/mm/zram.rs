@@ -0,0 +1,167 @@
+//! a zram like swap store: swapped out pages are compressed and kept on the
+//! kernel heap. The compression is a simple run length encoding over 64-bit
+//! words, which does well on the typical anonymous page (zeroes, small arrays,
+//! sparse data). A token is one header byte `h` followed by
+//! - `h & 0x80 != 0`: one word, repeated `(h & 0x7f) + 1` times
+//! - `h & 0x80 == 0`: `h + 1` literal words
+//!
+//! Pages that don't compress to [MAX_COMPRESSED] bytes are not swapped out.
+//! The store never allocates in a way that could panic on OOM: it's used when
+//! memory is tight.
+use crate::defs::*;
+use alloc::vec::Vec;
+use core::slice;
+use spin::Mutex;
+
+pub static ZRAM: Mutex<Zram> = Mutex::new(Zram::new());
+
+const WORDS: usize = (Mem::PAGE_SIZE / 8) as usize;
+const MAX_TOKEN_WORDS: usize = 128;
+const RUN: u8 = 0x80;
+/// storing a page must save at least a quarter of it
+pub const MAX_COMPRESSED: usize = (Mem::PAGE_SIZE as usize / 4) * 3;
+
+pub struct Zram {
+	slots: Vec<Option<Vec<u8>>>,
+	/// lowest slot that may be free
+	hint: usize,
+	/// number of pages in the store
+	pub nr_pages: u64,
+	/// compressed size of all pages in bytes
+	pub nr_bytes: u64,
+}
+
+impl Zram {
+	pub const fn new() -> Self {
+		Self {
+			slots: Vec::new(),
+			hint: 0,
+			nr_pages: 0,
+			nr_bytes: 0,
+		}
+	}
+
+	/// compress and store the page at kernel address `va`, returns the slot.
+	pub fn store(&mut self, va: u64) -> Result<u64, &'static str> {
+		let page = unsafe { &*(va as *const [u64; WORDS]) };
+		let mut len = 0;
+		if !compress(page, |b| len += b.len()) {
+			return Err("page not compressible");
+		}
+		let mut buf = Vec::new();
+		buf.try_reserve_exact(len).map_err(|_| "out of memory")?;
+		compress(page, |b| buf.extend_from_slice(b));
+		let slot =
+			match self.slots[self.hint..].iter().position(|s| s.is_none()) {
+				Some(i) => self.hint + i,
+				None => {
+					self.slots.try_reserve(1).map_err(|_| "out of memory")?;
+					self.slots.push(None);
+					self.slots.len() - 1
+				}
+			};
+		self.slots[slot] = Some(buf);
+		self.hint = slot + 1;
+		self.nr_pages += 1;
+		self.nr_bytes += len as u64;
+		Ok(slot as u64)
+	}
+
+	/// decompress the page in `slot` to the kernel address `va` and release
+	/// the slot.
+	pub fn load(&mut self, slot: u64, va: u64) -> Result<(), &'static str> {
+		let page = unsafe { &mut *(va as *mut [u64; WORDS]) };
+		let buf = self
+			.slots
+			.get(slot as usize)
+			.and_then(|s| s.as_ref())
+			.ok_or("bad swap slot")?;
+		decompress(buf, page)?;
+		self.free(slot);
+		Ok(())
+	}
+
+	/// drop the page in `slot`
+	pub fn free(&mut self, slot: u64) {
+		let Some(buf) =
+			self.slots.get_mut(slot as usize).and_then(|s| s.take())
+		else {
+			return;
+		};
+		self.nr_pages -= 1;
+		self.nr_bytes -= buf.len() as u64;
+		self.hint = usize::min(self.hint, slot as usize);
+	}
+}
+
+fn as_bytes(w: &[u64]) -> &[u8] {
+	unsafe { slice::from_raw_parts(w.as_ptr() as *const u8, w.len() * 8) }
+}
+
+/// feed the compressed page to `emit`, returns false (early) if the result
+/// would exceed [MAX_COMPRESSED].
+fn compress(page: &[u64; WORDS], mut emit: impl FnMut(&[u8])) -> bool {
+	let mut len = 0;
+	let mut i = 0;
+	while i < WORDS {
+		let mut run = 1;
+		while i + run < WORDS
+			&& run < MAX_TOKEN_WORDS
+			&& page[i + run] == page[i]
+		{
+			run += 1;
+		}
+		let (hdr, words) = if run > 1 {
+			(RUN | (run - 1) as u8, &page[i..i + 1])
+		} else {
+			// literals up to the next run
+			let start = i;
+			while i < WORDS
+				&& i - start < MAX_TOKEN_WORDS
+				&& !(i + 1 < WORDS && page[i + 1] == page[i])
+			{
+				i += 1;
+			}
+			run = 0;
+			((i - start - 1) as u8, &page[start..i])
+		};
+		len += 1 + words.len() * 8;
+		if len > MAX_COMPRESSED {
+			return false;
+		}
+		emit(&[hdr]);
+		emit(as_bytes(words));
+		i += run;
+	}
+	return true;
+}
+
+fn decompress(buf: &[u8], page: &mut [u64; WORDS]) -> Result<(), &'static str> {
+	let word = |off: usize| -> Result<u64, &'static str> {
+		let b = buf.get(off..off + 8).ok_or("truncated swap data")?;
+		Ok(u64::from_ne_bytes(b.try_into().unwrap()))
+	};
+	let (mut off, mut i) = (0, 0);
+	while off < buf.len() {
+		let hdr = buf[off];
+		off += 1;
+		let n = (hdr & !RUN) as usize + 1;
+		if i + n > WORDS {
+			return Err("corrupted swap data");
+		}
+		if hdr & RUN != 0 {
+			page[i..i + n].fill(word(off)?);
+			off += 8;
+		} else {
+			for w in &mut page[i..i + n] {
+				*w = word(off)?;
+				off += 8;
+			}
+		}
+		i += n;
+	}
+	if i != WORDS {
+		return Err("truncated swap data");
+	}
+	Ok(())
+}
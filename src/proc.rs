@@ -3,12 +3,14 @@
 use crate::arch::x86_64::is_int_enabled;
 use crate::defs;
 use crate::machine::keyctrl;
-use sync::bellringer;
 pub mod exec;
 pub mod loader;
+pub mod pid;
 pub mod sched;
 pub mod sync;
+pub mod syscall;
 pub mod task;
+pub mod timer;
 
 /// this is an optimization: reserve spaces in sync array to avoid runtime
 /// allocation inside of critical sections Note that the rust alloc collections
@@ -22,9 +24,8 @@ pub fn init() {
 		.lock()
 		.run_queue
 		.reserve(defs::Limits::SCHED_RUN_QUEUE_MIN_CAP);
-	bellringer::BELLRINGER
+	timer::TIMERS
 		.lock()
-		.bedroom
 		.reserve(defs::Limits::SEM_WAIT_QUEUE_MIN_CAP);
 	// semaphore has no "lock"
 	unsafe {
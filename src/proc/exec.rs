@@ -1,39 +1,59 @@
-//! exec a user program by loading the binary and replace the current address
-//! space
-//! TODO rework this code, this is only POC
+//! run user programs: [spawn_exec] starts a task that loads an elf from the
+//! ramfs into its address space and enters it in ring 3. The program leaves
+//! through the exit syscall, or is killed on a bad access.
 use crate::fs;
+use crate::fs::*;
+use crate::kthread::{spawn::spawn_entry, JoinHandle, KThread};
 use crate::proc::loader::load;
 use crate::proc::task::Task;
-use crate::{fs::*, Mem};
-use core::arch::asm;
-#[allow(unreachable_code)]
-pub fn exec(file_name: &str) {
-	let task = Task::current().unwrap();
-	let mm = &mut task.mm;
-	// wipe user vmas;
-	// NOTE this doesn't wipe the pagetables
-	mm.vmas
-		.retain(|vma| vma.vm_range.start >= Mem::ID_MAP_START);
+use crate::Mem;
+use alloc::boxed::Box;
+use alloc::string::String;
 
+/// exit code of a program that could not be loaded
+pub const EXIT_NOEXEC: i32 = 127;
+
+extern "C" {
+	/// see asm/vectors.s
+	fn enter_user(entry: u64, user_sp: u64, kstack_top: u64) -> !;
+}
+
+/// run the program `file_name` in a new task. Joining it gives the exit code.
+pub fn spawn_exec(file_name: &str) -> JoinHandle<()> {
+	let name = Box::into_raw(Box::new(String::from(file_name)));
+	spawn_entry(file_name, UserMain::get_entry(), name as u64)
+}
+
+/// the task entry of user programs, the file name is the entry argument
+struct UserMain {}
+impl KThread for UserMain {
+	fn entry() -> ! {
+		let t = Task::current().unwrap();
+		let name = unsafe { Box::from_raw(t.entry_arg() as *mut String) };
+		let entry = exec(&name);
+		// nothing on this stack is ever dropped after we leave
+		drop(name);
+		let Some(entry) = entry else {
+			t.exit(EXIT_NOEXEC);
+		};
+		let sp = Mem::USER_STACK_START + Mem::USER_STACK_SIZE;
+		unsafe { enter_user(entry, sp, t.kernel_stack_top()) };
+	}
+}
+
+/// load `file_name` into the address space of the current task, return the
+/// entry address
+pub fn exec(file_name: &str) -> Option<u64> {
 	let archive = get_archive();
 	let file = fs::iter(archive).find(|f| f.hdr.name() == file_name);
-	if file.is_none() {
+	let Some(f) = file else {
 		println!("error: no such file {}", file_name);
-		return;
-	}
-	let f = file.unwrap();
-	let entry = load(&f);
-	if entry.is_none() {
+		return None;
+	};
+	let Some(entry) = load(&f) else {
 		println!("failed to load elf");
-		return;
-	}
-	println!("exec entry: {:#X}", entry.unwrap());
-	go(entry.unwrap());
-	return;
-}
-
-extern "C" fn go(entry: u64) {
-	unsafe {
-		asm!("push {}; ret", in(reg) entry);
-	}
+		return None;
+	};
+	println!("exec entry: {:#X}", entry);
+	Some(entry)
 }
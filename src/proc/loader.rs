@@ -3,19 +3,33 @@ use crate::arch::x86_64::paging::get_root;
 use crate::arch::x86_64::paging::map_vma;
 use crate::black_magic;
 use crate::fs;
-use crate::mm::vmm::{VMArea, VMPerms, VMType};
+use crate::mm::vmm::{VMArea, VMFlags, VMPerms, VMType};
 use crate::proc::task::Task;
 use alloc::string::String;
 use core::ops::Range;
 use core::str::FromStr;
 use xmas_elf::header::HeaderPt2;
-use xmas_elf::program::ProgramHeader;
+use xmas_elf::program::{Flags, ProgramHeader};
 use xmas_elf::ElfFile;
 pub fn cat_elf(f: &fs::File) {
 	let elf = ElfFile::new(f.file).unwrap();
 	println!("{:?}", elf.header);
 }
 
+fn elf_perms(flags: Flags) -> VMPerms {
+	let mut perms = VMPerms::NONE;
+	if flags.is_read() {
+		perms |= VMPerms::R;
+	}
+	if flags.is_write() {
+		perms |= VMPerms::W;
+	}
+	if flags.is_execute() {
+		perms |= VMPerms::X;
+	}
+	perms
+}
+
 // this loads a file into task address space
 // half baked!
 // 0. find and parse elf
@@ -67,10 +81,11 @@ pub fn load(file: &fs::File) -> Option<u64> {
 				end: h.virtual_addr + h.mem_size,
 			},
 			tag: String::from_str("USER BITS").unwrap(),
-			user_perms: VMPerms::all(),
+			user_perms: elf_perms(h.flags),
 			backing: VMType::FILE(unsafe {
 				black_magic::make_static(&file.file[fstart..fend])
 			}),
+			flags: VMFlags::NONE,
 		};
 		let res = unsafe { map_vma(pt_root, &vma, true) };
 		if !res {
@@ -0,0 +1,134 @@
+//! process ids and the global task table. Every task gets a pid when it's
+//! created (see [Task::create_task]) and is registered in [TASK_TABLE] under
+//! that pid until it's removed. The table is the only way to find a task that
+//! is neither running nor queued (e.g. a sleeping one).
+//!
+//! PIDs are recycled, but not right away: the allocator keeps going upwards
+//! and wraps around at [PID_MAX], so a stale pid is unlikely to name a new
+//! task soon after the old one is gone.
+use crate::machine::interrupt::{irq_restore, irq_save};
+use crate::proc::sync::L3Sync;
+use crate::proc::task::{Task, TaskId};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// pids are 1 ~ PID_MAX - 1, 0 means "the calling task" in syscalls
+pub const PID_MAX: u32 = 32768;
+
+/// the table is touched from syscalls (L3), so we sync on L3 as well
+pub static TASK_TABLE: L3Sync<TaskTable> = L3Sync::new(TaskTable::new());
+
+/// bitmap pid allocator
+pub struct PidAllocator {
+	/// bit n is set if pid n is in use, allocated on first use
+	map: Vec<u64>,
+	/// the last allocated pid, we search from here on
+	last: u32,
+	nr_used: u32,
+}
+
+impl PidAllocator {
+	pub const fn new() -> Self { Self { map: Vec::new(), last: 0, nr_used: 0 } }
+
+	/// returns None if all pids are in use
+	pub fn alloc(&mut self) -> Option<u32> {
+		if self.map.is_empty() {
+			self.map = vec![0; PID_MAX as usize / 64];
+			// pid 0 is reserved
+			self.map[0] = 1;
+			self.nr_used = 1;
+		}
+		if self.nr_used == PID_MAX {
+			return None;
+		}
+		let mut pid = self.last;
+		loop {
+			pid = (pid + 1) % PID_MAX;
+			if !self.test(pid) {
+				break;
+			}
+		}
+		self.map[pid as usize / 64] |= 1 << (pid % 64);
+		self.nr_used += 1;
+		self.last = pid;
+		Some(pid)
+	}
+
+	pub fn free(&mut self, pid: u32) {
+		if pid == 0 || !self.test(pid) {
+			return;
+		}
+		self.map[pid as usize / 64] &= !(1 << (pid % 64));
+		self.nr_used -= 1;
+	}
+
+	fn test(&self, pid: u32) -> bool {
+		self.map
+			.get(pid as usize / 64)
+			.is_some_and(|w| w & (1 << (pid % 64)) != 0)
+	}
+}
+
+/// pid => task
+pub struct TaskTable {
+	pids: PidAllocator,
+	tasks: BTreeMap<u32, TaskId>,
+}
+
+impl TaskTable {
+	pub const fn new() -> Self {
+		Self {
+			pids: PidAllocator::new(),
+			tasks: BTreeMap::new(),
+		}
+	}
+
+	/// reserve a pid for a new task, register it with [Self::insert]
+	pub fn alloc_pid(&mut self) -> Option<u32> { self.pids.alloc() }
+
+	pub fn insert(&mut self, pid: u32, tid: TaskId) {
+		let old = self.tasks.insert(pid, tid);
+		debug_assert!(old.is_none(), "pid {} registered twice", pid);
+	}
+
+	/// unregister a task, its pid can be reused afterwards
+	pub fn remove(&mut self, pid: u32) -> Option<TaskId> {
+		let tid = self.tasks.remove(&pid)?;
+		self.pids.free(pid);
+		Some(tid)
+	}
+
+	pub fn get(&self, pid: u32) -> Option<TaskId> {
+		self.tasks.get(&pid).copied()
+	}
+
+	/// all tasks in pid order
+	pub fn iter(&self) -> impl Iterator<Item = (u32, TaskId)> + '_ {
+		self.tasks.iter().map(|(p, t)| (*p, *t))
+	}
+
+	pub fn len(&self) -> usize { self.tasks.len() }
+
+	pub fn is_empty(&self) -> bool { self.tasks.is_empty() }
+}
+
+/// find a task by pid, 0 is the current task
+pub fn lookup(pid: u32) -> Option<TaskId> {
+	if pid == 0 {
+		return Task::current().map(|t| t.taskid());
+	}
+	let r = irq_save();
+	let tid = TASK_TABLE.l3_get_ref().get(pid);
+	irq_restore(r);
+	tid
+}
+
+/// a snapshot of all tasks in pid order, for those who want to look at the
+/// tasks with interrupts enabled (e.g. to print them)
+pub fn tasks() -> Vec<TaskId> {
+	let r = irq_save();
+	let v = TASK_TABLE.l3_get_ref().iter().map(|(_, t)| t).collect();
+	irq_restore(r);
+	v
+}
@@ -1,10 +1,19 @@
+pub mod fair;
+pub mod prio;
+pub mod rt;
+use crate::arch::x86_64::gdt;
 use crate::arch::x86_64::is_int_enabled;
+use crate::arch::x86_64::paging::pcid;
+use crate::machine::clockevent;
 use crate::machine::interrupt::{irq_restore, irq_save};
+use crate::machine::time;
 use crate::proc::sync::*;
 use crate::proc::task::*;
-use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use core::sync::atomic::AtomicBool;
 use core::sync::atomic::Ordering;
+use rt::{DlParams, DlState, RtQueue, SchedError, SchedPolicy};
+use rt::{RR_SLICE_TICKS, RT_PRIO_MAX};
 pub static GLOBAL_SCHEDULER: L2Sync<Scheduler> = L2Sync::new(Scheduler::new());
 /// A global flag indicating whether reschedule is required.
 pub static NEED_RESCHEDULE: AtomicBool = AtomicBool::new(false);
@@ -24,8 +33,78 @@ pub fn CLEAR_NEED_RESCHEDULE() -> bool {
 	NEED_RESCHEDULE.swap(false, Ordering::Relaxed)
 }
 
+/// nice values, lower means higher priority
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+/// number of priority levels, level 0 is the highest
+pub const NR_PRIO: usize = (NICE_MAX - NICE_MIN + 1) as usize;
+
+/// the static priority (run queue level) of a nice value
+#[inline]
+pub fn nice_to_prio(nice: i8) -> u8 { (nice - NICE_MIN) as u8 }
+
+/// the scheduling policy, selected at build time
+#[cfg(not(feature = "sched_fair"))]
+pub type RunQueue = prio::PrioQueue;
+#[cfg(feature = "sched_fair")]
+pub type RunQueue = fair::FairQueue;
+
+/// per task state of the scheduling policies, part of the task struct
+#[derive(Debug, Default)]
+pub struct SchedEntity {
+	/// effective priority (run queue level), may be raised by aging
+	pub prio: u8,
+	/// run queue clock when the task was last enqueued
+	pub enqueued_at: u64,
+	/// weighted virtual runtime in ns
+	pub vruntime: u64,
+	/// when the task was last put on (or accounted on) the cpu, in ns
+	pub exec_start: u64,
+	/// real-time or normal
+	pub policy: SchedPolicy,
+	/// ticks left in the time slice of a SCHED_RR task
+	pub rr_slice: u32,
+	/// state of a deadline task
+	pub dl: DlState,
+}
+
+/// a scheduling policy: the run queue and the decision which task runs next.
+/// The running task is not in the queue. All methods except [Self::tick] are
+/// called with interrupts disabled.
+pub trait SchedClass {
+	/// make a task runnable
+	fn enqueue(&mut self, tid: TaskId);
+	/// remove a task from the queue, returns false if it's not queued
+	fn dequeue(&mut self, tid: TaskId) -> bool;
+	/// take the task to run next out of the queue
+	fn pick_next(&mut self) -> Option<TaskId>;
+	/// the running task `tid` gives up the cpu (before it's enqueued again or
+	/// goes to sleep)
+	fn put_prev(&mut self, _tid: TaskId) {}
+	/// timer tick while `curr` is running, called from the timer prologue.
+	/// Returns whether `curr` should be preempted. Must not touch the queue.
+	fn tick(&mut self, curr: TaskId) -> bool;
+	/// the nice value of `tid` has changed, it's not queued
+	fn renice(&mut self, tid: TaskId);
+	fn is_empty(&self) -> bool;
+	/// all queued tasks, in the order they would run
+	fn iter(&self) -> impl Iterator<Item = TaskId> + '_;
+}
+
 pub struct Scheduler {
-	pub run_queue: VecDeque<TaskId>,
+	/// the real-time class, takes precedence over the run queue
+	pub rt: RtQueue,
+	/// the normal policy
+	pub run_queue: RunQueue,
+	/// the idle task of this cpu: it runs only when nothing else is runnable
+	/// and is never enqueued.
+	pub idle: Option<TaskId>,
+	/// idle time (ns) accumulated so far
+	idle_ns: u64,
+	/// start of the current idle period, if the idle task is running
+	idle_since: Option<u64>,
+	/// dead tasks waiting for [Self::reap]
+	zombies: Vec<TaskId>,
 	pub need_schedule: bool,
 }
 
@@ -33,18 +112,227 @@ impl Scheduler {
 	pub const MIN_TASK_CAP: usize = 16;
 	pub const fn new() -> Self {
 		return Self {
-			run_queue: VecDeque::new(),
+			rt: RtQueue::new(),
+			run_queue: RunQueue::new(),
+			idle: None,
+			idle_ns: 0,
+			idle_since: None,
+			zombies: Vec::new(),
 			need_schedule: false,
 		};
 	}
 
 	// maybe we reject inserting existing tasks?
+	/// make a task runnable. If it's more important than the current one, we
+	/// reschedule at the next chance.
 	pub fn insert_task(&mut self, tid: TaskId) {
-		self.run_queue.push_back(tid);
+		if self.is_idle(tid) {
+			return;
+		}
+		let r = irq_save();
+		self.enqueue(tid);
+		if let Some(curr) = Task::current() {
+			if self.is_idle(curr.taskid())
+				|| RtQueue::preempts(tid.get_task_ref(), curr)
+			{
+				let _ = SET_NEED_RESCHEDULE();
+			}
+		}
+		irq_restore(r);
+	}
+
+	/// install the idle task, it must not be inserted into the run queue
+	pub fn set_idle(&mut self, tid: TaskId) { self.idle = Some(tid); }
+
+	#[inline]
+	pub fn is_idle(&self, tid: TaskId) -> bool { self.idle == Some(tid) }
+
+	/// total idle time in ns, including the current idle period
+	pub fn idle_time(&self) -> u64 {
+		let r = irq_save();
+		let mut ns = self.idle_ns;
+		if let Some(since) = self.idle_since {
+			ns += time::nsec() - since;
+		}
+		irq_restore(r);
+		ns
+	}
+
+	/// idle accounting on a switch from `prev` to `next`. The scheduler tick
+	/// stops while idle, unless throttled deadline tasks need it to be
+	/// released.
+	fn account_idle(&mut self, prev: TaskId, next: TaskId) {
+		let now = time::nsec();
+		if self.is_idle(prev) {
+			if let Some(since) = self.idle_since.take() {
+				self.idle_ns += now - since;
+			}
+			clockevent::start_tick();
+		}
+		if self.is_idle(next) {
+			self.idle_since = Some(now);
+			if !self.rt.has_throttled() {
+				clockevent::stop_tick();
+			}
+		}
+	}
+
+	/// the running task `tid` is exiting: it leaves its scheduling class and
+	/// waits to be reaped. Called on L2, [Self::do_schedule] must follow.
+	pub fn retire(&mut self, tid: TaskId) {
+		let r = irq_save();
+		let t = tid.get_task_ref_mut();
+		t.state = TaskState::Dead;
+		if t.se.policy == SchedPolicy::Deadline {
+			let _ = self.rt.admit(Some(&t.se.dl.params), None);
+		}
+		irq_restore(r);
+		self.zombies.push(tid);
+	}
+
+	/// destroy the dead tasks. They have switched away for good: a task
+	/// doesn't leave L2 between [Self::retire] and its last context swap.
+	/// Must be called in task context (L1), not by a dead task.
+	pub fn reap() {
+		let zombies = core::mem::take(&mut GLOBAL_SCHEDULER.lock().zombies);
+		for tid in zombies {
+			unsafe { Task::destroy(tid) };
+		}
+	}
+
+	pub fn try_remove(&mut self, tid: TaskId) -> bool {
+		let r = irq_save();
+		let removed = self.dequeue(tid);
+		irq_restore(r);
+		removed
+	}
+
+	/// set the nice value of a task, a queued task is requeued right away.
+	/// Returns the previous nice value.
+	pub fn set_nice(&mut self, tid: TaskId, nice: i8) -> i8 {
+		let nice = nice.clamp(NICE_MIN, NICE_MAX);
+		let r = irq_save();
+		let queued = self.dequeue(tid);
+		let t = tid.get_task_ref_mut();
+		let old = t.nice;
+		t.nice = nice;
+		self.run_queue.renice(tid);
+		if queued {
+			self.enqueue(tid);
+		}
+		irq_restore(r);
+		old
+	}
+
+	/// change the scheduling policy of a task. `dl` is required for (and only
+	/// used by) [SchedPolicy::Deadline]; a deadline task has to pass admission
+	/// control.
+	pub fn set_policy(
+		&mut self,
+		tid: TaskId,
+		policy: SchedPolicy,
+		dl: Option<DlParams>,
+	) -> Result<(), SchedError> {
+		match policy {
+			SchedPolicy::Fifo(p) | SchedPolicy::RoundRobin(p)
+				if p == 0 || p > RT_PRIO_MAX =>
+			{
+				return Err(SchedError::BadPrio);
+			}
+			SchedPolicy::Deadline => {
+				dl.ok_or(SchedError::NoParams)?.validate()?
+			}
+			_ => {}
+		}
+		let r = irq_save();
+		let t = tid.get_task_ref_mut();
+		let old =
+			(t.se.policy == SchedPolicy::Deadline).then_some(t.se.dl.params);
+		let new = dl.filter(|_| policy == SchedPolicy::Deadline);
+		if let Err(e) = self.rt.admit(old.as_ref(), new.as_ref()) {
+			irq_restore(r);
+			return Err(e);
+		}
+		let queued = self.dequeue(tid);
+		t.se.policy = policy;
+		t.se.rr_slice = RR_SLICE_TICKS;
+		if let Some(p) = new {
+			t.se.dl = DlState { params: p, ..DlState::default() };
+			// the running task isn't queued, but its first job starts now
+			// and it's charged from now on
+			if !queued && Task::current().is_some_and(|c| c.taskid() == tid) {
+				let now = time::nsec();
+				t.se.dl.replenish(now);
+				t.se.exec_start = now;
+			}
+		}
+		if queued {
+			self.enqueue(tid);
+		}
+		// the running task may no longer be the most important one
+		let _ = SET_NEED_RESCHEDULE();
+		irq_restore(r);
+		Ok(())
+	}
+
+	/// timer tick, called from the timer prologue (L3)
+	pub fn tick() {
+		let Some(curr) = Task::current() else {
+			return;
+		};
+		curr.stats.update(time::nsec());
+		let sched = unsafe { GLOBAL_SCHEDULER.get_ref_mut_unguarded() };
+		let tid = curr.taskid();
+		let preempt = if sched.is_idle(tid) {
+			!sched.rt.is_empty()
+				|| !sched.run_queue.is_empty()
+				|| sched.rt.has_due(time::nsec())
+		} else if curr.se.policy.is_rt() {
+			sched.rt.tick(tid)
+		} else {
+			// both: the normal policy does its accounting
+			sched.run_queue.tick(tid) | sched.rt.tick(tid)
+		};
+		if preempt {
+			let _ = SET_NEED_RESCHEDULE();
+		}
+	}
+
+	/// the queued tasks, real-time ones first
+	pub fn runnable(&self) -> impl Iterator<Item = TaskId> + '_ {
+		self.rt.iter().chain(self.run_queue.iter())
+	}
+
+	fn enqueue(&mut self, tid: TaskId) {
+		if tid.get_task_ref().se.policy.is_rt() {
+			self.rt.enqueue(tid);
+		} else {
+			self.run_queue.enqueue(tid);
+		}
+	}
+
+	fn dequeue(&mut self, tid: TaskId) -> bool {
+		if tid.get_task_ref().se.policy.is_rt() {
+			self.rt.dequeue(tid)
+		} else {
+			self.run_queue.dequeue(tid)
+		}
+	}
+
+	fn put_prev(&mut self, tid: TaskId) {
+		if tid.get_task_ref().se.policy.is_rt() {
+			self.rt.put_prev(tid);
+		} else {
+			self.run_queue.put_prev(tid);
+		}
 	}
 
-	pub fn try_remove(&mut self, _tid: TaskId) {
-		todo!("not implemented");
+	/// the idle task if nothing else is runnable
+	fn pick_next(&mut self) -> Option<TaskId> {
+		self.rt
+			.pick_next()
+			.or_else(|| self.run_queue.pick_next())
+			.or(self.idle)
 	}
 
 	/// unsafe because this must be called on a linearization point on Epilogue
@@ -77,16 +365,29 @@ impl Scheduler {
 			// begin L3 critical section
 			// make sure we drop the mutable borrow before doing context swap
 			let sched = GLOBAL_SCHEDULER.get_ref_mut_unguarded();
-			if sched.run_queue.is_empty() && me.state == TaskState::Run {
+			if sched.rt.is_empty()
+				&& sched.run_queue.is_empty()
+				&& me.state == TaskState::Run
+				&& !sched.rt.must_switch(me.taskid())
+			{
 				// I'm the only one, just return;
 				irq_restore(r);
 				return;
 			}
-			next_tid = sched.run_queue.pop_front().expect("no runnable task");
+			if !sched.is_idle(me.taskid()) {
+				sched.put_prev(me.taskid());
+				if me.state == TaskState::Run {
+					sched.enqueue(me.taskid());
+				}
+			}
+			next_tid = sched.pick_next().expect("no runnable task");
+			sched.account_idle(me.taskid(), next_tid);
 			next_task = next_tid.get_task_ref_mut();
 			debug_assert_eq!(next_task.state, TaskState::Run);
-			if me.state == TaskState::Run {
-				sched.run_queue.push_back(me.taskid());
+			if next_tid != me.taskid() {
+				let now = time::nsec();
+				me.stats.switch_out(now, me.state != TaskState::Run);
+				next_task.stats.switch_in(now);
 			}
 			// end L3 critical section
 			irq_restore(r);
@@ -94,7 +395,11 @@ impl Scheduler {
 		if me.taskid() == next_task.taskid() {
 			return;
 		}
+		if me.pt_root != next_task.pt_root {
+			pcid::switch_to(next_task.pt_root, next_task.asid);
+		}
 		unsafe {
+			gdt::set_tss_ksp(next_task.kernel_stack_top());
 			context_swap(
 				&(me.context) as *const _ as u64,
 				&(next_task.context) as *const _ as u64,
@@ -120,16 +425,22 @@ impl Scheduler {
 		// well, the "LEAVE_L2" call in the task entries logically release
 		// the GLOBAL_SCHEDULER but semantically that's too weird
 		let sched = GLOBAL_SCHEDULER.get_ref_mut_unguarded();
-		let tid = sched
-			.run_queue
-			.pop_front()
-			.expect("run queue empty, can't start");
+		let tid = sched.pick_next().expect("run queue empty, can't start");
+		if sched.is_idle(tid) {
+			sched.idle_since = Some(time::nsec());
+			if !sched.rt.has_throttled() {
+				clockevent::stop_tick();
+			}
+		}
 		let first_task = tid.get_task_ref_mut();
+		first_task.stats.switch_in(time::nsec());
 		irq_restore(irq);
 		// kickoff simulates a do_schedule, so we need to enter l2 here.
 		// new tasks must leave l2 explicitly on their first run
 		ENTER_L2();
+		pcid::switch_to(first_task.pt_root, first_task.asid);
 		unsafe {
+			gdt::set_tss_ksp(first_task.kernel_stack_top());
 			context_swap_to(&(first_task.context) as *const _ as u64);
 		}
 	}
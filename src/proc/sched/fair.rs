@@ -0,0 +1,89 @@
+//! a fair scheduler (feature `sched_fair`), modeled after linux' CFS. Every
+//! task accumulates virtual runtime: the time it ran, scaled by the inverse
+//! of its weight (from the nice value). The runnable task with the smallest
+//! vruntime runs next; the queue is a tree ordered by vruntime.
+use super::{nice_to_prio, SchedClass};
+use crate::machine::time;
+use crate::proc::task::TaskId;
+use alloc::collections::BTreeSet;
+
+/// weight of nice 0
+const NICE_0_WEIGHT: u64 = 1024;
+/// weight per nice value (-20 ~ 19), each step is about 10% cpu time
+const PRIO_TO_WEIGHT: [u64; 40] = [
+	88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548,
+	7620, 6100, 4904, 3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526,
+	423, 335, 272, 215, 172, 137, 110, 87, 70, 56, 45, 36, 29, 23, 18, 15,
+];
+/// a waking task is placed at most half of this behind the fastest one, so
+/// that sleeping doesn't earn unlimited credit
+const SCHED_LATENCY_NS: u64 = 40_000_000;
+
+pub struct FairQueue {
+	/// (vruntime, task)
+	tree: BTreeSet<(u64, TaskId)>,
+	/// monotonic lower bound of the vruntime of all runnable tasks
+	min_vruntime: u64,
+}
+
+impl FairQueue {
+	pub const fn new() -> Self {
+		Self {
+			tree: BTreeSet::new(),
+			min_vruntime: 0,
+		}
+	}
+
+	/// the tree allocates per node, nothing to reserve here
+	pub fn reserve(&mut self, _cap: usize) {}
+
+	/// charge the running task for the time since it was last accounted
+	fn update_curr(&mut self, tid: TaskId) {
+		let t = tid.get_task_ref_mut();
+		let now = time::nsec();
+		let delta = now.saturating_sub(t.se.exec_start);
+		let weight = PRIO_TO_WEIGHT[nice_to_prio(t.nice) as usize];
+		t.se.vruntime += delta * NICE_0_WEIGHT / weight;
+		t.se.exec_start = now;
+	}
+}
+
+impl SchedClass for FairQueue {
+	fn enqueue(&mut self, tid: TaskId) {
+		let se = &mut tid.get_task_ref_mut().se;
+		let floor = self.min_vruntime.saturating_sub(SCHED_LATENCY_NS / 2);
+		se.vruntime = u64::max(se.vruntime, floor);
+		self.tree.insert((se.vruntime, tid));
+	}
+
+	fn dequeue(&mut self, tid: TaskId) -> bool {
+		let vr = tid.get_task_ref().se.vruntime;
+		self.tree.remove(&(vr, tid))
+	}
+
+	fn pick_next(&mut self) -> Option<TaskId> {
+		let (vr, tid) = self.tree.pop_first()?;
+		self.min_vruntime = u64::max(self.min_vruntime, vr);
+		tid.get_task_ref_mut().se.exec_start = time::nsec();
+		Some(tid)
+	}
+
+	fn put_prev(&mut self, tid: TaskId) { self.update_curr(tid); }
+
+	/// preempt once the running task is no longer the leftmost
+	fn tick(&mut self, curr: TaskId) -> bool {
+		self.update_curr(curr);
+		let vr = curr.get_task_ref().se.vruntime;
+		self.tree.first().is_some_and(|(left, _)| *left < vr)
+	}
+
+	/// the weight is looked up on accounting, nothing to do
+	fn renice(&mut self, _tid: TaskId) {}
+
+	fn is_empty(&self) -> bool { self.tree.is_empty() }
+
+	/// smallest vruntime first
+	fn iter(&self) -> impl Iterator<Item = TaskId> + '_ {
+		self.tree.iter().map(|(_, t)| *t)
+	}
+}
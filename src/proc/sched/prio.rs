@@ -0,0 +1,111 @@
+//! priority round robin: one round robin queue per priority level, the
+//! highest non-empty level is always picked first. To prevent starvation,
+//! the longest waiting task of each lower level moves up one level every
+//! [AGING_PICKS] picks. A picked task falls back to its static priority.
+use super::{nice_to_prio, SchedClass, NR_PRIO};
+use crate::proc::task::TaskId;
+use alloc::collections::VecDeque;
+
+/// a task that waited this many picks in the run queue moves up one level
+pub const AGING_PICKS: u64 = 8;
+
+pub struct PrioQueue {
+	levels: [VecDeque<TaskId>; NR_PRIO],
+	/// bit n is set if level n is not empty
+	bitmap: u64,
+	/// number of picks so far, the clock of aging
+	picks: u64,
+}
+
+impl PrioQueue {
+	pub const fn new() -> Self {
+		Self {
+			levels: [const { VecDeque::new() }; NR_PRIO],
+			bitmap: 0,
+			picks: 0,
+		}
+	}
+
+	/// reserve capacity on every level
+	pub fn reserve(&mut self, cap: usize) {
+		for q in self.levels.iter_mut() {
+			q.reserve(cap);
+		}
+	}
+
+	fn take_front(&mut self, lv: usize) -> TaskId {
+		let tid = self.levels[lv].pop_front().unwrap();
+		if self.levels[lv].is_empty() {
+			self.bitmap &= !(1 << lv);
+		}
+		tid
+	}
+
+	fn age(&mut self) {
+		// level 0 can't go any higher
+		let mut bits = self.bitmap & !1;
+		while bits != 0 {
+			let lv = bits.trailing_zeros() as usize;
+			bits &= bits - 1;
+			let se = &self.levels[lv][0].get_task_ref().se;
+			if self.picks - se.enqueued_at < AGING_PICKS {
+				continue;
+			}
+			let tid = self.take_front(lv);
+			tid.get_task_ref_mut().se.prio = (lv - 1) as u8;
+			self.enqueue(tid);
+		}
+	}
+}
+
+impl SchedClass for PrioQueue {
+	/// enqueue at the task's current (effective) priority
+	fn enqueue(&mut self, tid: TaskId) {
+		let se = &mut tid.get_task_ref_mut().se;
+		let lv = se.prio as usize;
+		se.enqueued_at = self.picks;
+		self.levels[lv].push_back(tid);
+		self.bitmap |= 1 << lv;
+	}
+
+	fn dequeue(&mut self, tid: TaskId) -> bool {
+		let lv = tid.get_task_ref().se.prio as usize;
+		let q = &mut self.levels[lv];
+		let Some(i) = q.iter().position(|t| *t == tid) else {
+			return false;
+		};
+		q.remove(i);
+		if q.is_empty() {
+			self.bitmap &= !(1 << lv);
+		}
+		return true;
+	}
+
+	fn pick_next(&mut self) -> Option<TaskId> {
+		if self.bitmap == 0 {
+			return None;
+		}
+		self.picks += 1;
+		self.age();
+		let lv = self.bitmap.trailing_zeros() as usize;
+		let tid = self.take_front(lv);
+		let t = tid.get_task_ref_mut();
+		t.se.prio = nice_to_prio(t.nice);
+		Some(tid)
+	}
+
+	/// round robin: every tick is the end of a time slice
+	fn tick(&mut self, _curr: TaskId) -> bool { true }
+
+	fn renice(&mut self, tid: TaskId) {
+		let t = tid.get_task_ref_mut();
+		t.se.prio = nice_to_prio(t.nice);
+	}
+
+	fn is_empty(&self) -> bool { self.bitmap == 0 }
+
+	/// highest priority first
+	fn iter(&self) -> impl Iterator<Item = TaskId> + '_ {
+		self.levels.iter().flatten().copied()
+	}
+}
@@ -0,0 +1,348 @@
+//! real-time scheduling class, it always takes precedence over the normal
+//! policy. Within the class:
+//! 1. deadline tasks (EDF): each task declares a runtime budget per period
+//!    and a relative deadline. The task with the earliest absolute deadline
+//!    runs first. A task that used up its budget is throttled until its next
+//!    period, so it can't hurt the others. Admission control keeps the total
+//!    bandwidth (sum of runtime / period) below [BW_LIMIT].
+//! 2. fixed priority tasks (FIFO and RR), priority 1 ~ [RT_PRIO_MAX], higher
+//!    is more important. FIFO tasks run until they block or a more important
+//!    task becomes runnable; RR tasks also take turns every [RR_SLICE_TICKS].
+//!
+//! A deadline miss (a job still unfinished at its absolute deadline) is
+//! recorded in the task and in [DL_MISSES].
+use super::SchedClass;
+use crate::machine::time;
+use crate::proc::task::{Task, TaskId};
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// highest fixed real-time priority
+pub const RT_PRIO_MAX: u8 = 63;
+/// time slice of SCHED_RR tasks
+pub const RR_SLICE_TICKS: u32 = 5;
+/// bandwidth is fixed point, this is 100%
+pub const BW_UNIT: u64 = 1 << 20;
+/// leave some cpu time to the normal tasks
+pub const BW_LIMIT: u64 = BW_UNIT * 95 / 100;
+
+/// total number of deadline misses
+pub static DL_MISSES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SchedPolicy {
+	/// handled by the normal policy (priority round robin or fair)
+	#[default]
+	Normal,
+	/// fixed priority, run to completion
+	Fifo(u8),
+	/// fixed priority, round robin among the same priority
+	RoundRobin(u8),
+	/// earliest deadline first, see [DlParams]
+	Deadline,
+}
+
+impl SchedPolicy {
+	#[inline]
+	pub fn is_rt(&self) -> bool { *self != SchedPolicy::Normal }
+}
+
+/// why a policy change is refused
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SchedError {
+	/// fixed priority out of 1 ~ [RT_PRIO_MAX]
+	BadPrio,
+	/// a deadline policy without [DlParams]
+	NoParams,
+	/// not 0 < runtime <= deadline <= period
+	BadParams,
+	/// admission control: the deadline bandwidth would exceed [BW_LIMIT]
+	NoBandwidth,
+}
+
+/// parameters of a deadline task, in ns
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DlParams {
+	/// budget per period
+	pub runtime: u64,
+	/// relative deadline of each job
+	pub deadline: u64,
+	pub period: u64,
+}
+
+impl DlParams {
+	pub fn validate(&self) -> Result<(), SchedError> {
+		if self.runtime == 0
+			|| self.runtime > self.deadline
+			|| self.deadline > self.period
+		{
+			return Err(SchedError::BadParams);
+		}
+		Ok(())
+	}
+
+	/// utilization in [BW_UNIT]
+	pub fn bandwidth(&self) -> u64 { self.runtime * BW_UNIT / self.period }
+}
+
+/// per task state of the deadline class
+#[derive(Debug, Default)]
+pub struct DlState {
+	pub params: DlParams,
+	/// release time of the current job
+	pub release: u64,
+	/// absolute deadline of the current job
+	pub deadline: u64,
+	/// runtime left in the current period
+	pub budget: i64,
+	/// the current job has missed its deadline (already recorded)
+	pub missed: bool,
+	/// number of jobs that missed their deadline
+	pub misses: u64,
+}
+
+impl DlState {
+	/// start a new job if the current period is over
+	pub fn replenish(&mut self, now: u64) {
+		let p = self.params.period;
+		if self.release != 0 && now < self.release + p {
+			return;
+		}
+		if self.release == 0 {
+			self.release = now;
+		} else {
+			self.release += (now - self.release) / p * p;
+		}
+		self.deadline = self.release + self.params.deadline;
+		self.budget = self.params.runtime as i64;
+		self.missed = false;
+	}
+
+	fn check_miss(&mut self, now: u64) {
+		if now > self.deadline && !self.missed {
+			self.missed = true;
+			self.misses += 1;
+			DL_MISSES.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	fn throttled(&self) -> bool { self.budget <= 0 }
+}
+
+pub struct RtQueue {
+	/// runnable deadline tasks: (absolute deadline, task)
+	dl: BTreeSet<(u64, TaskId)>,
+	/// deadline tasks out of budget, until their next period
+	throttled: Vec<TaskId>,
+	/// fixed priority levels, index = priority
+	levels: [VecDeque<TaskId>; RT_PRIO_MAX as usize + 1],
+	/// bit n is set if level n is not empty
+	bitmap: u64,
+	/// admitted deadline bandwidth in [BW_UNIT]
+	pub total_bw: u64,
+}
+
+impl RtQueue {
+	pub const fn new() -> Self {
+		Self {
+			dl: BTreeSet::new(),
+			throttled: Vec::new(),
+			levels: [const { VecDeque::new() }; RT_PRIO_MAX as usize + 1],
+			bitmap: 0,
+			total_bw: 0,
+		}
+	}
+
+	/// admission control: account the bandwidth of a task becoming a deadline
+	/// task (`new`) and of one leaving the class (`old`)
+	pub fn admit(
+		&mut self,
+		old: Option<&DlParams>,
+		new: Option<&DlParams>,
+	) -> Result<(), SchedError> {
+		let old_bw = old.map_or(0, |p| p.bandwidth());
+		let new_bw = new.map_or(0, |p| p.bandwidth());
+		let total = self.total_bw - old_bw + new_bw;
+		if new.is_some() && total > BW_LIMIT {
+			return Err(SchedError::NoBandwidth);
+		}
+		self.total_bw = total;
+		Ok(())
+	}
+
+	/// a throttled task has reached its next period
+	pub fn has_due(&self, now: u64) -> bool {
+		self.throttled.iter().any(|t| {
+			let dl = &t.get_task_ref().se.dl;
+			now >= dl.release + dl.params.period
+		})
+	}
+
+	/// deadline tasks are waiting for their next period
+	pub fn has_throttled(&self) -> bool { !self.throttled.is_empty() }
+
+	/// whether the running task `curr` must leave the cpu even if nothing
+	/// else is queued: a deadline task out of budget, or a throttled task has
+	/// reached its next period. Charges a deadline task.
+	pub fn must_switch(&self, curr: TaskId) -> bool {
+		let now = time::nsec();
+		if curr.get_task_ref().se.policy == SchedPolicy::Deadline {
+			Self::account(curr, now);
+			if curr.get_task_ref().se.dl.throttled() {
+				return true;
+			}
+		}
+		self.has_due(now)
+	}
+
+	/// whether `new` should run instead of `curr` right away
+	pub fn preempts(new: &Task, curr: &Task) -> bool {
+		use SchedPolicy::*;
+		match (new.se.policy, curr.se.policy) {
+			(Deadline, Deadline) => new.se.dl.deadline < curr.se.dl.deadline,
+			(Deadline, _) => true,
+			(_, Deadline) => false,
+			(Fifo(p) | RoundRobin(p), Fifo(q) | RoundRobin(q)) => p > q,
+			(Fifo(_) | RoundRobin(_), Normal) => true,
+			(Normal, _) => false,
+		}
+	}
+
+	fn release_throttled(&mut self, now: u64) {
+		let mut i = 0;
+		while i < self.throttled.len() {
+			let tid = self.throttled[i];
+			let dl = &mut tid.get_task_ref_mut().se.dl;
+			dl.replenish(now);
+			if dl.throttled() {
+				i += 1;
+				continue;
+			}
+			self.throttled.swap_remove(i);
+			self.dl.insert((dl.deadline, tid));
+		}
+	}
+
+	/// charge a running deadline task
+	fn account(tid: TaskId, now: u64) {
+		let se = &mut tid.get_task_ref_mut().se;
+		se.dl.budget -= now.saturating_sub(se.exec_start) as i64;
+		se.exec_start = now;
+		se.dl.check_miss(now);
+	}
+}
+
+impl SchedClass for RtQueue {
+	fn enqueue(&mut self, tid: TaskId) {
+		let se = &mut tid.get_task_ref_mut().se;
+		match se.policy {
+			SchedPolicy::Deadline => {
+				se.dl.replenish(time::nsec());
+				if se.dl.throttled() {
+					self.throttled.push(tid);
+				} else {
+					self.dl.insert((se.dl.deadline, tid));
+				}
+			}
+			SchedPolicy::Fifo(p) | SchedPolicy::RoundRobin(p) => {
+				self.levels[p as usize].push_back(tid);
+				self.bitmap |= 1 << p;
+			}
+			SchedPolicy::Normal => unreachable!("normal task in rt queue"),
+		}
+	}
+
+	fn dequeue(&mut self, tid: TaskId) -> bool {
+		let se = &tid.get_task_ref().se;
+		match se.policy {
+			SchedPolicy::Deadline => {
+				if let Some(i) = self.throttled.iter().position(|t| *t == tid) {
+					self.throttled.swap_remove(i);
+					return true;
+				}
+				self.dl.remove(&(se.dl.deadline, tid))
+			}
+			SchedPolicy::Fifo(p) | SchedPolicy::RoundRobin(p) => {
+				let q = &mut self.levels[p as usize];
+				let Some(i) = q.iter().position(|t| *t == tid) else {
+					return false;
+				};
+				q.remove(i);
+				if q.is_empty() {
+					self.bitmap &= !(1 << p);
+				}
+				true
+			}
+			SchedPolicy::Normal => false,
+		}
+	}
+
+	fn pick_next(&mut self) -> Option<TaskId> {
+		let now = time::nsec();
+		self.release_throttled(now);
+		if let Some((_, tid)) = self.dl.pop_first() {
+			let se = &mut tid.get_task_ref_mut().se;
+			se.dl.check_miss(now);
+			se.exec_start = now;
+			return Some(tid);
+		}
+		if self.bitmap == 0 {
+			return None;
+		}
+		let p = 63 - self.bitmap.leading_zeros() as usize;
+		let tid = self.levels[p].pop_front().unwrap();
+		if self.levels[p].is_empty() {
+			self.bitmap &= !(1 << p);
+		}
+		Some(tid)
+	}
+
+	fn put_prev(&mut self, tid: TaskId) {
+		if tid.get_task_ref().se.policy == SchedPolicy::Deadline {
+			Self::account(tid, time::nsec());
+		}
+	}
+
+	fn tick(&mut self, curr: TaskId) -> bool {
+		let now = time::nsec();
+		let due = self.has_due(now);
+		match curr.get_task_ref().se.policy {
+			SchedPolicy::Deadline => {
+				Self::account(curr, now);
+				let dl = &curr.get_task_ref().se.dl;
+				let earlier =
+					self.dl.first().is_some_and(|(d, _)| *d < dl.deadline);
+				dl.throttled() || due || earlier
+			}
+			SchedPolicy::RoundRobin(_) => {
+				let se = &mut curr.get_task_ref_mut().se;
+				se.rr_slice = se.rr_slice.saturating_sub(1);
+				if se.rr_slice == 0 {
+					se.rr_slice = RR_SLICE_TICKS;
+					return true;
+				}
+				due
+			}
+			SchedPolicy::Fifo(_) => due,
+			// a normal task is running: preempt if we have something
+			SchedPolicy::Normal => due || !self.is_empty(),
+		}
+	}
+
+	/// nice values don't matter here
+	fn renice(&mut self, _tid: TaskId) {}
+
+	/// doesn't count throttled tasks, they are not runnable
+	fn is_empty(&self) -> bool { self.dl.is_empty() && self.bitmap == 0 }
+
+	/// deadline tasks by deadline, then fixed priority tasks by priority, then
+	/// the throttled ones
+	fn iter(&self) -> impl Iterator<Item = TaskId> + '_ {
+		self.dl
+			.iter()
+			.map(|(_, t)| *t)
+			.chain(self.levels.iter().rev().flatten().copied())
+			.chain(self.throttled.iter().copied())
+	}
+}
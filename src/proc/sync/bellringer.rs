@@ -1,14 +1,11 @@
 //! bellringer puts tasks to sleep and wake them when semptepber ends
-//! the bellringer is very much like a SleepSemaphore
+//! the bellringer is very much like a SleepSemaphore. It's a thin layer on top
+//! of the kernel timers: every sleeper is a one-shot timer that wakes it up.
 use crate::machine::time;
-use crate::proc::sync::L2Sync;
 use crate::proc::task::TaskId;
-use alloc::collections::VecDeque;
+use crate::proc::timer::{self, TimerId};
 
-pub static BELLRINGER: L2Sync<BellRinger> = L2Sync::new(BellRinger::new());
-pub struct BellRinger {
-	pub bedroom: VecDeque<Sleeper>,
-}
+pub struct BellRinger {}
 
 #[derive(Copy, Clone, Debug)]
 pub struct Sleeper {
@@ -23,23 +20,16 @@ impl Sleeper {
 }
 
 impl BellRinger {
-	pub const fn new() -> Self { Self { bedroom: VecDeque::new() } }
-
-	pub fn check_in(s: Sleeper) { BELLRINGER.lock().bedroom.push_back(s); }
-
-	/// check the sleeper queue and wake up if timer is due.
-	/// this is only to be called in epilogues
-	pub unsafe fn check_all() {
-		// there is much room for optimization here: the queue can be sorted and
-		// instead of absolute time we can store the differntial. But I'll keep
-		// it simple here.
-		let now = time::nsec();
-		BELLRINGER.get_ref_mut_unguarded().bedroom.retain(|x| {
-			if x.until > now {
-				true
-			} else {
-				x.tid.get_task_ref_mut().wakeup();
-				false
+	/// ring the bell for `s` when it's time. The sleeper must be waiting by
+	/// then, in other words call this on L2 right before the task leaves the
+	/// cpu.
+	pub fn check_in(s: Sleeper) -> TimerId {
+		let ns = s.until.saturating_sub(time::nsec());
+		let tid = s.tid;
+		timer::add(ns, move || {
+			// the task may be gone while sleeping
+			if let Some(t) = tid.get_mut() {
+				unsafe { t.wakeup() };
 			}
 		})
 	}
@@ -75,8 +75,12 @@ impl<T> SleepSemaphore<T> {
 
 	unsafe fn wakeup_one(&self) {
 		let wq = &mut *self.wait_room.get();
-		if let Some(t) = wq.pop_front() {
-			t.get_task_ref_mut().wakeup();
+		// skip the waiters that are gone
+		while let Some(t) = wq.pop_front() {
+			if let Some(t) = t.get_mut() {
+				t.wakeup();
+				break;
+			}
 		}
 	}
 
@@ -0,0 +1,373 @@
+//! system calls: `int 0x80` with the syscall number in rax and the arguments in
+//! rdi, rsi, rdx, r10, r8 and r9 (like linux). The result is returned in rax,
+//! negative values are errors.
+//!
+//! Syscalls are handled with interrupts disabled (L3), they must be short. The
+//! ones that take locks or allocate memory (shm) enable interrupts and run like
+//! task code.
+//!
+//! Pointer arguments must point into a VMA of the calling task that allows the
+//! access, anything else is EFAULT.
+use crate::arch::x86_64::arch_regs::TrapFrame;
+use crate::defs::Mem;
+use crate::machine::interrupt::{interrupt_disable, interrupt_enable};
+use crate::machine::time;
+use crate::mm::shm::{self, ShmError};
+use crate::mm::vmm::VMPerms;
+use crate::proc::pid;
+use crate::proc::sched::rt::{DlParams, SchedError, SchedPolicy};
+use crate::proc::sched::{Scheduler, GLOBAL_SCHEDULER};
+use crate::proc::task::Task;
+use alloc::string::String;
+use core::arch::asm;
+use core::slice;
+
+/// syscall numbers
+pub mod nr {
+	/// `nice(pid, nice) -> old nice`: set the nice value of task `pid`, or the
+	/// calling task if `pid` is 0
+	pub const NICE: u64 = 0;
+	/// `clock_gettime(clock, *mut Timespec) -> 0`: read [super::CLOCK_REALTIME]
+	/// or [super::CLOCK_MONOTONIC]
+	pub const CLOCK_GETTIME: u64 = 1;
+	/// `gettimeofday(*mut Timeval) -> 0`: the realtime in microseconds
+	pub const GETTIMEOFDAY: u64 = 2;
+	/// `shm_open(name, name_len, size) -> id`: open the shared memory segment
+	/// `name`, create it with `size` bytes if it doesn't exist
+	pub const SHM_OPEN: u64 = 3;
+	/// `shm_map(id, addr, prot) -> addr`: map segment `id` at `addr` (0: pick
+	/// one) with [super::PROT_READ] / [super::PROT_WRITE]
+	pub const SHM_MAP: u64 = 4;
+	/// `shm_unmap(addr) -> 0`: remove the shm mapping at `addr`
+	pub const SHM_UNMAP: u64 = 5;
+	/// `shm_unlink(name, name_len) -> 0`: remove the name of a segment
+	pub const SHM_UNLINK: u64 = 6;
+	/// `sched_setattr(pid, policy, prio, runtime, deadline, period) -> 0`: set
+	/// the scheduling policy of task `pid` (0: the calling task). `prio` is
+	/// for [super::SCHED_FIFO] and [super::SCHED_RR], the times (ns) are for
+	/// [super::SCHED_DEADLINE].
+	pub const SCHED_SETATTR: u64 = 7;
+	/// `sched_yield() -> 0`: give up the cpu. A deadline task is done with its
+	/// current job and sleeps until its next period.
+	pub const SCHED_YIELD: u64 = 8;
+	/// `exit(code)`: end the calling task, never returns
+	pub const EXIT: u64 = 9;
+	/// `write(fd, buf, len) -> len`: write to [super::STDOUT] or
+	/// [super::STDERR], which both go to the console
+	pub const WRITE: u64 = 10;
+}
+
+/// clocks of clock_gettime
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+/// protection bits of shm_map
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+
+/// policies of sched_setattr
+pub const SCHED_NORMAL: u64 = 0;
+pub const SCHED_FIFO: u64 = 1;
+pub const SCHED_RR: u64 = 2;
+pub const SCHED_DEADLINE: u64 = 6;
+
+/// file descriptors of write
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// longest shm name
+const NAME_MAX: u64 = 255;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Timespec {
+	pub tv_sec: i64,
+	pub tv_nsec: i64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Timeval {
+	pub tv_sec: i64,
+	pub tv_usec: i64,
+}
+
+pub const ENOENT: i64 = -2;
+pub const ESRCH: i64 = -3;
+pub const EBADF: i64 = -9;
+pub const ENOMEM: i64 = -12;
+pub const EFAULT: i64 = -14;
+pub const EBUSY: i64 = -16;
+pub const EINVAL: i64 = -22;
+pub const ENOSYS: i64 = -38;
+
+/// entry from the trap gate
+pub fn dispatch(frame: &mut TrapFrame) {
+	let args = [
+		frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+	];
+	let ret = match frame.rax {
+		nr::NICE => sys_nice(args[0] as u32, args[1] as i64),
+		nr::CLOCK_GETTIME => sys_clock_gettime(args[0], args[1]),
+		nr::GETTIMEOFDAY => sys_gettimeofday(args[0]),
+		nr::SHM_OPEN => preemptible(|| sys_shm_open(args[0], args[1], args[2])),
+		nr::SHM_MAP => preemptible(|| sys_shm_map(args[0], args[1], args[2])),
+		nr::SHM_UNMAP => preemptible(|| sys_shm_unmap(args[0])),
+		nr::SHM_UNLINK => preemptible(|| sys_shm_unlink(args[0], args[1])),
+		nr::SCHED_SETATTR => sys_sched_setattr(args),
+		nr::SCHED_YIELD => preemptible(sys_sched_yield),
+		nr::EXIT => sys_exit(args[0] as i32),
+		nr::WRITE => preemptible(|| sys_write(args[0], args[1], args[2])),
+		_ => ENOSYS,
+	};
+	frame.rax = ret as u64;
+}
+
+/// run a syscall that takes locks or allocates with interrupts enabled, like
+/// task code
+fn preemptible(f: impl FnOnce() -> i64) -> i64 {
+	interrupt_enable();
+	let r = f();
+	interrupt_disable();
+	r
+}
+
+/// [ptr, ptr + len) is user memory in a single VMA of the calling task that
+/// allows `perms`
+fn user_range_ok(ptr: u64, len: u64, perms: VMPerms) -> bool {
+	let Some(end) = ptr.checked_add(len) else {
+		return false;
+	};
+	if ptr == 0 || end > Mem::USER_END {
+		return false;
+	}
+	let Some(t) = Task::current() else {
+		return false;
+	};
+	t.mm.vmas.iter().any(|v| {
+		v.vm_range.start <= ptr
+			&& end <= v.vm_range.end
+			&& v.user_perms.contains(perms)
+	})
+}
+
+/// copy a name from user memory
+fn user_name(ptr: u64, len: u64) -> Result<String, i64> {
+	if len == 0 || len > NAME_MAX {
+		return Err(EINVAL);
+	}
+	if !user_range_ok(ptr, len, VMPerms::R) {
+		return Err(EFAULT);
+	}
+	let bytes =
+		unsafe { slice::from_raw_parts(ptr as *const u8, len as usize) };
+	let name = core::str::from_utf8(bytes).map_err(|_| EINVAL)?;
+	Ok(String::from(name))
+}
+
+fn shm_errno(e: ShmError) -> i64 {
+	match e {
+		ShmError::NoSegment => ENOENT,
+		ShmError::NoMemory | ShmError::NoSpace => ENOMEM,
+		ShmError::ZeroSize | ShmError::BadAddress | ShmError::NotMapped => {
+			EINVAL
+		}
+	}
+}
+
+fn sys_shm_open(name: u64, name_len: u64, size: u64) -> i64 {
+	let name = match user_name(name, name_len) {
+		Ok(n) => n,
+		Err(e) => return e,
+	};
+	match shm::open(&name, size) {
+		Ok(id) => id as i64,
+		Err(e) => shm_errno(e),
+	}
+}
+
+fn sys_shm_map(id: u64, addr: u64, prot: u64) -> i64 {
+	let Ok(id) = u32::try_from(id) else {
+		return EINVAL;
+	};
+	let mut perms = VMPerms::NONE;
+	if prot & PROT_READ != 0 {
+		perms |= VMPerms::R;
+	}
+	if prot & PROT_WRITE != 0 {
+		perms |= VMPerms::W;
+	}
+	if perms.is_empty() {
+		return EINVAL;
+	}
+	let addr = if addr == 0 { None } else { Some(addr) };
+	let t = Task::current().unwrap();
+	match shm::map(&mut t.mm, t.pt_root, id, addr, perms) {
+		Ok(va) => va as i64,
+		Err(e) => shm_errno(e),
+	}
+}
+
+fn sys_shm_unmap(addr: u64) -> i64 {
+	let t = Task::current().unwrap();
+	match shm::unmap(&mut t.mm, t.pt_root, addr) {
+		Ok(()) => 0,
+		Err(e) => shm_errno(e),
+	}
+}
+
+fn sys_shm_unlink(name: u64, name_len: u64) -> i64 {
+	let name = match user_name(name, name_len) {
+		Ok(n) => n,
+		Err(e) => return e,
+	};
+	match shm::unlink(&name) {
+		Ok(()) => 0,
+		Err(e) => shm_errno(e),
+	}
+}
+
+fn sys_nice(pid: u32, nice: i64) -> i64 {
+	let Ok(nice) = i8::try_from(nice) else {
+		return EINVAL;
+	};
+	// we are on L3: the scheduler can't be in use by anyone else
+	let sched = unsafe { GLOBAL_SCHEDULER.get_ref_mut_unguarded() };
+	let Some(tid) = pid::lookup(pid) else {
+		return ESRCH;
+	};
+	sched.set_nice(tid, nice) as i64
+}
+
+fn sys_sched_setattr(args: [u64; 6]) -> i64 {
+	let [pid, policy, prio, runtime, deadline, period] = args;
+	let Ok(prio) = u8::try_from(prio) else {
+		return EINVAL;
+	};
+	let (policy, dl) = match policy {
+		SCHED_NORMAL => (SchedPolicy::Normal, None),
+		SCHED_FIFO => (SchedPolicy::Fifo(prio), None),
+		SCHED_RR => (SchedPolicy::RoundRobin(prio), None),
+		SCHED_DEADLINE => {
+			let p = DlParams { runtime, deadline, period };
+			(SchedPolicy::Deadline, Some(p))
+		}
+		_ => return EINVAL,
+	};
+	// we are on L3: the scheduler can't be in use by anyone else
+	let sched = unsafe { GLOBAL_SCHEDULER.get_ref_mut_unguarded() };
+	let Some(tid) = pid::lookup(pid as u32) else {
+		return ESRCH;
+	};
+	match sched.set_policy(tid, policy, dl) {
+		Ok(()) => 0,
+		Err(SchedError::NoBandwidth) => EBUSY,
+		Err(_) => EINVAL,
+	}
+}
+
+fn sys_sched_yield() -> i64 {
+	let t = Task::current().unwrap();
+	if t.se.policy == SchedPolicy::Deadline {
+		t.wait_next_period();
+	} else {
+		Scheduler::yield_cpu();
+	}
+	0
+}
+
+fn sys_exit(code: i32) -> ! {
+	// like a killed task, we leave with interrupts enabled as in task code
+	interrupt_enable();
+	Task::current().unwrap().exit(code);
+}
+
+fn sys_write(fd: u64, buf: u64, len: u64) -> i64 {
+	if fd != STDOUT && fd != STDERR {
+		return EBADF;
+	}
+	if len == 0 {
+		return 0;
+	}
+	if !user_range_ok(buf, len, VMPerms::R) {
+		return EFAULT;
+	}
+	let bytes =
+		unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+	let Ok(s) = core::str::from_utf8(bytes) else {
+		return EINVAL;
+	};
+	print!("{}", s);
+	len as i64
+}
+
+/// the user buffer at `ptr` for a T to be written, None if it's not aligned
+/// or not in a writable VMA of the calling task
+fn user_buf<'a, T>(ptr: u64) -> Option<&'a mut T> {
+	if ptr & (core::mem::align_of::<T>() as u64 - 1) != 0 {
+		return None;
+	}
+	if !user_range_ok(ptr, core::mem::size_of::<T>() as u64, VMPerms::W) {
+		return None;
+	}
+	Some(unsafe { &mut *(ptr as *mut T) })
+}
+
+fn sys_clock_gettime(clock: u64, ts: u64) -> i64 {
+	let ns = match clock {
+		CLOCK_REALTIME => time::realtime_nsec(),
+		CLOCK_MONOTONIC => time::nsec(),
+		_ => return EINVAL,
+	};
+	let Some(ts) = user_buf::<Timespec>(ts) else {
+		return EFAULT;
+	};
+	ts.tv_sec = (ns / 1_000_000_000) as i64;
+	ts.tv_nsec = (ns % 1_000_000_000) as i64;
+	0
+}
+
+fn sys_gettimeofday(tv: u64) -> i64 {
+	let ns = time::realtime_nsec();
+	let Some(tv) = user_buf::<Timeval>(tv) else {
+		return EFAULT;
+	};
+	tv.tv_sec = (ns / 1_000_000_000) as i64;
+	tv.tv_usec = (ns % 1_000_000_000 / 1000) as i64;
+	0
+}
+
+/// issue a syscall with up to 3 arguments
+#[inline]
+pub fn syscall3(nr: u64, a0: u64, a1: u64, a2: u64) -> i64 {
+	let ret: u64;
+	unsafe {
+		asm!(
+			"int 0x80",
+			inout("rax") nr => ret,
+			in("rdi") a0,
+			in("rsi") a1,
+			in("rdx") a2,
+		)
+	};
+	ret as i64
+}
+
+/// issue a syscall with up to 6 arguments
+#[inline]
+pub fn syscall6(nr: u64, a: [u64; 6]) -> i64 {
+	let ret: u64;
+	unsafe {
+		asm!(
+			"int 0x80",
+			inout("rax") nr => ret,
+			in("rdi") a[0],
+			in("rsi") a[1],
+			in("rdx") a[2],
+			in("r10") a[3],
+			in("r8") a[4],
+			in("r9") a[5],
+		)
+	};
+	ret as i64
+}
@@ -1,61 +1,235 @@
 use crate::arch::x86_64::arch_regs::Context64;
+use crate::arch::x86_64::paging;
+use crate::arch::x86_64::paging::pcid::ASID_ALLOCATOR;
 use crate::arch::x86_64::{arch_regs, is_int_enabled};
-use crate::mm::vmm::{VMArea, VMMan, VMPerms, VMType};
+use crate::machine::interrupt::{irq_restore, irq_save};
+use crate::machine::time;
+use crate::mm::shm;
+use crate::mm::vmm::{VMArea, VMFlags, VMMan, VMPerms, VMType};
 use crate::mm::KSTACK_ALLOCATOR;
-use crate::proc::sched::GLOBAL_SCHEDULER;
+use crate::proc::pid::TASK_TABLE;
+use crate::proc::sched::rt::SchedPolicy;
+use crate::proc::sched::{nice_to_prio, SchedEntity, GLOBAL_SCHEDULER};
 use crate::proc::sync::bellringer::{BellRinger, Sleeper};
+use crate::proc::sync::{ENTER_L2, LEAVE_L2};
 use crate::{defs::*, Scheduler};
+use alloc::boxed::Box;
 use alloc::collections::VecDeque;
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::ops::Range;
 use core::ptr;
 use core::str::FromStr;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 /// currently only kernelSp and Context are important.
 /// the task struct will be placed on the starting addr (low addr) of the kernel stack.
 /// therefore we can retrive the task struct at anytime by masking the kernel stack
 /// NOTE: we assume all fields in [Task] are only modified by the task itself,
 /// i.e. no task should modify another task's state. (this may change though, in
 /// which case we will need some atomics)
-/// TODO: the mm is heap allocated object (vec of vmas). But the task struct
-/// doesn't have a lifetime. Must cleanup the memory used by the mm itself when
-/// exiting a task.
+/// A task ends with [Task::exit]; what it owns (the mm, the page tables, the
+/// kernel stack...) is freed later by [Task::destroy], once it no longer runs.
 #[repr(C)]
 pub struct Task {
 	pub magic: u64,
 	pub pid: u32,
+	/// handle of this task
+	pub id: TaskId,
+	pub name: String,
 	/// note that this points to the stack bottom (low addr)
 	pub kernel_stack: u64,
 	pub mm: VMMan,
+	/// page table root (virtual address), see [paging::new_root]
+	pub pt_root: u64,
+	/// address space id, used as PCID when switching to `pt_root`
+	pub asid: u16,
 	// pub user_stack: u64,
 	pub state: TaskState,
+	/// -20 (highest priority) ~ 19
+	pub nice: i8,
+	/// state of the scheduling policy
+	pub se: SchedEntity,
+	pub stats: TaskStats,
+	/// set by [Task::exit], 0 for a regular exit
+	pub exit_code: i32,
+	/// called by [Task::exit] with the exit code, in task context (e.g. to
+	/// wake up the joiners of a spawned thread)
+	pub on_exit: Option<Box<dyn FnOnce(i32)>>,
 	pub context: arch_regs::Context64,
 }
 
+/// exit code of a task killed by the kernel (e.g. on a bad memory access), as
+/// a shell would report SIGKILL
+pub const EXIT_KILLED: i32 = 128 + 9;
+
 /// not to confuse with a integer TID. A TaskID identifies a task and __locate__
-/// it. In this case the TaskID wraps around the task struct's address. The
-/// reason why the scheduler doesn't directly store `Box<Task>` (or alike) is that
-/// the smart pointer types automatically drops the owned values when their
-/// lifetime end. For now want to have manual control of when, where and how I
-/// drop the Task because there could be more plans than just freeing the memory
+/// it. The reason why the scheduler doesn't directly store `Box<Task>` (or
+/// alike) is that the smart pointer types automatically drops the owned values
+/// when their lifetime end. For now want to have manual control of when, where
+/// and how I drop the Task because there could be more plans than just freeing
+/// the memory.
+///
+/// A TaskId is a handle: an index into [TASK_SLOTS] plus the generation of the
+/// slot at the time the task was created. When a task is gone its slot gets a
+/// new generation, so a stale TaskId (e.g. left in a wait room) no longer
+/// resolves, instead of pointing into a reused kernel stack. Resolving a
+/// handle is two atomic loads, it's fine to do so in epilogues.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub struct TaskId(u64);
+pub struct TaskId {
+	slot: u32,
+	gen: u32,
+}
+
+/// where the task structs are, see [TaskId]
+pub static TASK_SLOTS: [TaskSlot; Limits::MAX_TASKS] =
+	[const { TaskSlot::new() }; Limits::MAX_TASKS];
+
+pub struct TaskSlot {
+	/// bumped every time the slot is released
+	gen: AtomicU32,
+	/// address of the task struct, 0 if the slot is free
+	task: AtomicU64,
+}
+
+impl TaskSlot {
+	const fn new() -> Self {
+		Self {
+			gen: AtomicU32::new(0),
+			task: AtomicU64::new(0),
+		}
+	}
+}
 
 impl TaskId {
-	pub fn new(addr: u64) -> Self { Self(addr) }
+	/// take a free slot for the task struct at `addr`. None if there are
+	/// already [Limits::MAX_TASKS] tasks.
+	fn alloc(addr: u64) -> Option<Self> {
+		for (i, s) in TASK_SLOTS.iter().enumerate() {
+			if s.task
+				.compare_exchange(0, addr, Ordering::Acquire, Ordering::Relaxed)
+				.is_ok()
+			{
+				let gen = s.gen.load(Ordering::Relaxed);
+				return Some(Self { slot: i as u32, gen });
+			}
+		}
+		None
+	}
+
+	/// invalidate this and all copies of this handle and free the slot. The
+	/// task struct must not be used through the handle afterwards.
+	pub fn release(&self) {
+		let s = &TASK_SLOTS[self.slot as usize];
+		if s.gen
+			.compare_exchange(
+				self.gen,
+				self.gen.wrapping_add(1),
+				Ordering::Release,
+				Ordering::Relaxed,
+			)
+			.is_ok()
+		{
+			s.task.store(0, Ordering::Release);
+		}
+	}
+
+	fn addr(&self) -> Option<u64> {
+		let s = TASK_SLOTS.get(self.slot as usize)?;
+		if s.gen.load(Ordering::Acquire) != self.gen {
+			return None;
+		}
+		let addr = s.task.load(Ordering::Acquire);
+		if addr == 0 {
+			return None;
+		}
+		return Some(addr);
+	}
+
+	/// whether the task still exists
+	#[inline]
+	pub fn is_alive(&self) -> bool { self.addr().is_some() }
+
+	/// resolve the handle, None if the task is gone
+	pub fn get(&self) -> Option<&Task> {
+		return self.addr().map(|a| unsafe { &*(a as *const Task) });
+	}
+
+	/// resolve the handle, None if the task is gone
+	pub fn get_mut(&self) -> Option<&mut Task> {
+		return self.addr().map(|a| unsafe { &mut *(a as *mut Task) });
+	}
 
+	/// for those who know the task is alive, e.g. because it's in the run
+	/// queue. Panics on a stale handle.
 	pub fn get_task_ref(&self) -> &Task {
-		return unsafe { &*(self.0 as *mut Task) };
+		return self.get().expect("stale task id");
 	}
 
+	/// see [Self::get_task_ref]
 	pub fn get_task_ref_mut(&self) -> &mut Task {
-		return unsafe { &mut *(self.0 as *mut Task) };
+		return self.get_mut().expect("stale task id");
+	}
+}
+
+/// cpu accounting of a task, times in ns. Updated on context switches and on
+/// timer ticks.
+#[derive(Debug, Default, Clone)]
+pub struct TaskStats {
+	/// time spent on the cpu
+	pub runtime: u64,
+	/// when the task was put on the cpu or last accounted
+	pub since: u64,
+	/// switches away because the task blocked, slept or exited
+	pub nvcsw: u64,
+	/// switches away because the task was preempted or yielded
+	pub nivcsw: u64,
+	/// when the task was woken up, 0 if it isn't waiting for the cpu
+	pub woken_at: u64,
+	/// wakeup latency: from wakeup to running
+	pub nr_wakeups: u64,
+	pub wakeup_total: u64,
+	pub wakeup_max: u64,
+}
+
+impl TaskStats {
+	/// charge the time since the last update, the task is running
+	pub fn update(&mut self, now: u64) {
+		self.runtime += now.saturating_sub(self.since);
+		self.since = now;
+	}
+
+	/// the task is put on the cpu
+	pub fn switch_in(&mut self, now: u64) {
+		self.since = now;
+		if self.woken_at != 0 {
+			let lat = now.saturating_sub(self.woken_at);
+			self.nr_wakeups += 1;
+			self.wakeup_total += lat;
+			self.wakeup_max = u64::max(self.wakeup_max, lat);
+			self.woken_at = 0;
+		}
+	}
+
+	/// the task leaves the cpu, `voluntary` if it's no longer runnable
+	pub fn switch_out(&mut self, now: u64, voluntary: bool) {
+		self.update(now);
+		if voluntary {
+			self.nvcsw += 1;
+		} else {
+			self.nivcsw += 1;
+		}
+	}
+
+	/// average wakeup latency
+	pub fn wakeup_avg(&self) -> u64 {
+		self.wakeup_total.checked_div(self.nr_wakeups).unwrap_or(0)
 	}
 }
 
 /// currently don't differentiate between running and ready states because the
 /// scheduler push the next task to the back of the queue. i.e. the running task
 /// is also "ready" in the run_queue
-#[derive(Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TaskState {
 	Run,
 	Wait,
@@ -85,10 +259,14 @@ impl Task {
 
 	/// settle_on_stack and prepare_context must be called before switching to
 	/// the task. TODO: combine them into one single API
+	/// `arg` is left on top of the stack for the entry, see [Self::entry_arg]
 	#[inline(always)]
-	fn prepare_context(&mut self, entry: u64) {
+	fn prepare_context(&mut self, entry: u64, arg: u64) {
 		let mut sp = self.get_init_kernel_sp();
 		unsafe {
+			// 16 bytes to keep the stack aligned
+			sp -= 16;
+			*(sp as *mut u64) = arg;
 			sp -= 8;
 			*(sp as *mut u64) = 0;
 			sp -= 8;
@@ -97,6 +275,20 @@ impl Task {
 		self.context.rsp = sp;
 	}
 
+	/// the argument given to [Self::create_task_arg]
+	#[inline]
+	pub fn entry_arg(&self) -> u64 {
+		unsafe { *((self.get_init_kernel_sp() - 16) as *const u64) }
+	}
+
+	/// the top of the kernel stack: the cpu puts the trap frame there when the
+	/// task is interrupted in ring 3, see
+	/// [crate::arch::x86_64::gdt::set_tss_ksp]
+	#[inline]
+	pub fn kernel_stack_top(&self) -> u64 {
+		self.kernel_stack + Mem::KERNEL_STACK_SIZE
+	}
+
 	/// get kernel stack top (high addr) to initialize the new task Note that
 	/// there are often alignment requirements of stack pointer. We do
 	/// 8 bytes here
@@ -125,7 +317,7 @@ impl Task {
 	}
 
 	#[inline]
-	pub fn taskid(&self) -> TaskId { TaskId::new(self as *const _ as u64) }
+	pub fn taskid(&self) -> TaskId { self.id }
 
 	/// a task may be present in multiple wait rooms; this is logically not
 	/// possible at the moment, but would be necessary for stuffs like EPoll.
@@ -146,34 +338,75 @@ impl Task {
 		}
 		// TODO: makesure you don't put a task in the run queue more than once.
 		self.state = TaskState::Run;
+		self.stats.woken_at = time::nsec();
 		let sched = GLOBAL_SCHEDULER.get_ref_mut_unguarded();
 		sched.insert_task(self.taskid());
 	}
 
 	pub fn nanosleep(&mut self, ns: u64) {
 		debug_assert!(self.state == TaskState::Run);
+		debug_assert!(is_int_enabled());
+		// check in and leave the cpu in one go on L2, so that the bell can't
+		// ring before we are gone
+		ENTER_L2();
 		self.state = TaskState::Wait;
 		BellRinger::check_in(Sleeper::new(self.taskid(), ns));
-		debug_assert!(is_int_enabled());
+		unsafe { Scheduler::do_schedule() };
+		LEAVE_L2();
+	}
+
+	/// a deadline task is done with its current job, it's throttled until its
+	/// next period.
+	pub fn wait_next_period(&mut self) {
+		debug_assert!(self.se.policy == SchedPolicy::Deadline);
+		self.se.dl.budget = 0;
 		Scheduler::yield_cpu();
 	}
 
 	/// create a kernel thread, you need to add it to the scheduler run queue
-	/// manually
-	pub fn create_task(pid: u32, entry: u64) -> TaskId {
+	/// manually. The task gets a fresh pid and is registered in the task table.
+	pub fn create_task(name: &str, entry: u64) -> TaskId {
+		Self::create_task_arg(name, entry, 0)
+	}
+
+	/// like [Self::create_task], the entry gets `arg` through
+	/// [Self::entry_arg]
+	pub fn create_task_arg(name: &str, entry: u64, arg: u64) -> TaskId {
 		let sp = unsafe { KSTACK_ALLOCATOR.lock().allocate() };
-		let tid = TaskId::new(sp);
-		println!("new task on {:#X}", sp);
+		let tid = TaskId::alloc(sp).expect("too many tasks");
+		let r = irq_save();
+		let table = TASK_TABLE.l3_get_ref_mut();
+		let pid = table.alloc_pid().expect("out of pids");
+		table.insert(pid, tid);
+		irq_restore(r);
+		println!("new task {} on {:#X}", pid, sp);
+		let pt_root = paging::new_root().expect("can't allocate page table");
+		let asid = ASID_ALLOCATOR.lock().alloc(pt_root);
+		#[cfg(feature = "kpti")]
+		paging::kpti::new_user_root(pt_root, sp)
+			.expect("can't allocate user page table");
 		let nt = unsafe {
 			Task::settle_on_stack(
 				sp,
 				Task {
 					magic: Mem::KERNEL_STACK_TASK_MAGIC,
 					pid,
+					id: tid,
+					name: String::from(name),
 					kernel_stack: sp,
 					state: TaskState::Run,
+					nice: 0,
+					se: SchedEntity {
+						prio: nice_to_prio(0),
+						..SchedEntity::default()
+					},
+					stats: TaskStats::default(),
+					exit_code: 0,
+					on_exit: None,
 					context: Context64::default(),
 					mm: VMMan::new(),
+					pt_root,
+					asid,
 				},
 			)
 		};
@@ -186,6 +419,7 @@ impl Task {
 			tag: String::from_str("KERNEL IDMAP").unwrap(),
 			user_perms: VMPerms::NONE,
 			backing: VMType::ANOM,
+			flags: VMFlags::NONE,
 		});
 		// KERNEL
 		nt.mm.vmas.push(VMArea {
@@ -196,8 +430,9 @@ impl Task {
 			tag: String::from_str("KERNEL").unwrap(),
 			user_perms: VMPerms::NONE,
 			backing: VMType::ANOM,
+			flags: VMFlags::NONE,
 		});
-		// KERNEL
+		// USER STACK
 		nt.mm.vmas.push(VMArea {
 			vm_range: Range::<u64> {
 				start: Mem::USER_STACK_START,
@@ -206,8 +441,57 @@ impl Task {
 			tag: String::from_str("USER STACK").unwrap(),
 			user_perms: VMPerms::R | VMPerms::W,
 			backing: VMType::ANOM,
+			flags: VMFlags::GROWSDOWN,
 		});
-		nt.prepare_context(entry);
+		nt.prepare_context(entry, arg);
 		tid
 	}
+
+	/// terminate the current task with `code`. We are still on its stack, so
+	/// it's only marked dead here and destroyed later by [Scheduler::reap].
+	pub fn exit(&mut self, code: i32) -> ! {
+		debug_assert!(is_int_enabled());
+		self.exit_code = code;
+		if let Some(f) = self.on_exit.take() {
+			f(code);
+		}
+		// the next task leaves L2 for us
+		ENTER_L2();
+		unsafe {
+			let sched = GLOBAL_SCHEDULER.get_ref_mut_unguarded();
+			sched.retire(self.taskid());
+			Scheduler::do_schedule();
+		}
+		unreachable!("dead task scheduled");
+	}
+
+	/// free everything a dead task owns and invalidate its handle.
+	/// unsafe: the task must not run anymore. Call this in task context (not
+	/// L2 or L3) because it frees memory.
+	pub unsafe fn destroy(tid: TaskId) {
+		let Some(t) = tid.get_mut() else {
+			return;
+		};
+		debug_assert_eq!(t.state, TaskState::Dead);
+		let r = irq_save();
+		TASK_TABLE.l3_get_ref_mut().remove(t.pid);
+		irq_restore(r);
+		let shm: Vec<u64> =
+			t.mm.vmas
+				.iter()
+				.filter(|v| matches!(v.backing, VMType::SHM(_)))
+				.map(|v| v.vm_range.start)
+				.collect();
+		for addr in shm {
+			let _ = shm::unmap(&mut t.mm, t.pt_root, addr);
+		}
+		ASID_ALLOCATOR.lock().free(t.pt_root);
+		#[cfg(feature = "kpti")]
+		paging::kpti::free_user_root(t.pt_root);
+		paging::free_root(t.pt_root);
+		let sp = t.kernel_stack;
+		ptr::drop_in_place(t);
+		tid.release();
+		KSTACK_ALLOCATOR.lock().free(sp);
+	}
 }
@@ -0,0 +1,199 @@
+//! kernel timers: one-shot and periodic callbacks that run in epilogue (L2)
+//! context when they are due, e.g. for key repeat, watchdogs or retransmits.
+//!
+//! The pending timers are kept in a min-heap ordered by expiry time. Cancel and
+//! modify don't search the heap: the timer itself lives in a map and a heap
+//! entry that doesn't match the timer (anymore) is simply skipped when it
+//! comes up. The timer epilogue calls [run_expired]; the timer interrupt is
+//! programmed for the earliest expiry (see [clockevent]).
+//!
+//! A callback must be short and must not block, it may add, modify or cancel
+//! timers (including its own).
+use crate::machine::clockevent;
+use crate::machine::time;
+use crate::proc::sync::{L2Sync, IS_L2_AVAILABLE};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BinaryHeap};
+use core::cmp::Reverse;
+
+pub static TIMERS: L2Sync<TimerQueue> = L2Sync::new(TimerQueue::new());
+
+pub type Callback = Box<dyn FnMut() + Send>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+struct Timer {
+	/// absolute expiry time in ns
+	expires: u64,
+	/// re-arm after firing, in ns
+	period: Option<u64>,
+	callback: Callback,
+}
+
+pub struct TimerQueue {
+	/// (expiry, timer), may contain stale entries
+	heap: BinaryHeap<Reverse<(u64, TimerId)>>,
+	timers: BTreeMap<TimerId, Timer>,
+	next_id: u64,
+	/// the timer whose callback is running, it's not in `timers` meanwhile
+	running: Option<TimerId>,
+	/// the running timer was cancelled by its callback
+	running_cancelled: bool,
+	/// the running timer was modified by its callback to expire then
+	running_expires: Option<u64>,
+}
+
+/// the callbacks are only ever touched on L2, one at a time
+unsafe impl Sync for TimerQueue {}
+
+impl TimerQueue {
+	pub const fn new() -> Self {
+		Self {
+			heap: BinaryHeap::new(),
+			timers: BTreeMap::new(),
+			next_id: 1,
+			running: None,
+			running_cancelled: false,
+			running_expires: None,
+		}
+	}
+
+	pub fn reserve(&mut self, n: usize) { self.heap.reserve(n); }
+
+	fn add(
+		&mut self,
+		expires: u64,
+		period: Option<u64>,
+		cb: Callback,
+	) -> TimerId {
+		let id = TimerId(self.next_id);
+		self.next_id += 1;
+		self.heap.push(Reverse((expires, id)));
+		self.timers
+			.insert(id, Timer { expires, period, callback: cb });
+		id
+	}
+
+	fn cancel(&mut self, id: TimerId) -> bool {
+		if self.running == Some(id) {
+			self.running_cancelled = true;
+			return true;
+		}
+		self.timers.remove(&id).is_some()
+	}
+
+	fn modify(&mut self, id: TimerId, expires: u64) -> bool {
+		if self.running == Some(id) && !self.running_cancelled {
+			self.running_expires = Some(expires);
+			return true;
+		}
+		let Some(t) = self.timers.get_mut(&id) else {
+			return false;
+		};
+		t.expires = expires;
+		self.heap.push(Reverse((expires, id)));
+		true
+	}
+
+	/// the earliest expiry time of the pending timers
+	pub fn next_expiry(&mut self) -> Option<u64> {
+		self.pop_stale();
+		self.heap.peek().map(|Reverse((e, _))| *e)
+	}
+
+	pub fn len(&self) -> usize { self.timers.len() }
+
+	pub fn is_empty(&self) -> bool { self.timers.is_empty() }
+
+	/// drop the heap entries of cancelled and modified timers
+	fn pop_stale(&mut self) {
+		while let Some(Reverse((e, id))) = self.heap.peek() {
+			if self.timers.get(id).is_some_and(|t| t.expires == *e) {
+				return;
+			}
+			self.heap.pop();
+		}
+	}
+
+	/// take the next timer that's due at `now`
+	fn pop_expired(&mut self, now: u64) -> Option<(TimerId, Timer)> {
+		self.pop_stale();
+		let Reverse((e, id)) = *self.heap.peek()?;
+		if e > now {
+			return None;
+		}
+		self.heap.pop();
+		let t = self.timers.remove(&id)?;
+		Some((id, t))
+	}
+}
+
+/// access the timers from task context or from an epilogue
+/// and tell the clock event layer about the next expiry
+fn with_timers<R>(f: impl FnOnce(&mut TimerQueue) -> R) -> R {
+	let run = |q: &mut TimerQueue| {
+		let r = f(q);
+		clockevent::set_next_timer(q.next_expiry());
+		r
+	};
+	if IS_L2_AVAILABLE() {
+		let mut q = TIMERS.lock();
+		run(&mut q)
+	} else {
+		// we are on L2 already, prologues must not get here
+		run(unsafe { TIMERS.get_ref_mut_unguarded() })
+	}
+}
+
+/// run `cb` once after `ns` nanoseconds
+pub fn add(ns: u64, cb: impl FnMut() + Send + 'static) -> TimerId {
+	let expires = time::nsec() + ns;
+	with_timers(|q| q.add(expires, None, Box::new(cb)))
+}
+
+/// run `cb` every `period` nanoseconds, starting one period from now
+pub fn add_periodic(period: u64, cb: impl FnMut() + Send + 'static) -> TimerId {
+	let expires = time::nsec() + period;
+	with_timers(|q| q.add(expires, Some(period), Box::new(cb)))
+}
+
+/// stop a pending timer, returns false if it's not pending (anymore)
+pub fn cancel(id: TimerId) -> bool { with_timers(|q| q.cancel(id)) }
+
+/// let a pending timer expire `ns` nanoseconds from now instead, a periodic
+/// timer keeps its period after that. Returns false if it's not pending.
+pub fn modify(id: TimerId, ns: u64) -> bool {
+	let expires = time::nsec() + ns;
+	with_timers(|q| q.modify(id, expires))
+}
+
+/// run the callbacks of all due timers. Only call this in epilogues.
+pub unsafe fn run_expired() {
+	let now = time::nsec();
+	loop {
+		let q = TIMERS.get_ref_mut_unguarded();
+		let Some((id, mut t)) = q.pop_expired(now) else {
+			clockevent::set_next_timer(q.next_expiry());
+			return;
+		};
+		q.running = Some(id);
+		q.running_cancelled = false;
+		q.running_expires = None;
+		// the callback may use the timer API, don't hold on to the queue
+		(t.callback)();
+		let q = TIMERS.get_ref_mut_unguarded();
+		q.running = None;
+		if q.running_cancelled {
+			continue;
+		}
+		t.expires = match (q.running_expires, t.period) {
+			(Some(e), _) => e,
+			// don't try to catch up on missed periods
+			(None, Some(p)) => u64::max(t.expires + p, now + 1),
+			(None, None) => continue,
+		};
+		q.heap.push(Reverse((t.expires, id)));
+		q.timers.insert(id, t);
+	}
+}